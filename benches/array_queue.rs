@@ -0,0 +1,135 @@
+//! Throughput of ArrayQueue against the queues in crossbeam and std
+//!
+//! crossbeam 0.2 predates crossbeam-channel, so its closest counterparts
+//! are SegQueue and MsQueue, with std's sync_channel as the bounded baseline.
+
+#![feature(test)]
+
+extern crate test;
+extern crate crossbeam;
+extern crate exclusive_ptr;
+
+use std::sync::mpsc::sync_channel;
+
+use test::Bencher;
+use crossbeam::scope;
+use crossbeam::sync::{MsQueue, SegQueue};
+use exclusive_ptr::collections::ArrayQueue;
+
+const NUM_MSGS: usize = 10000;
+const NUM_THREADS: usize = 2;
+const CAPACITY: usize = 128;
+
+#[bench]
+fn array_queue_spsc(b: &mut Bencher) {
+    let queue = ArrayQueue::new(CAPACITY);
+    b.iter(|| {
+        scope(|scope| {
+            scope.spawn(|| {
+                for i in 0..NUM_MSGS {
+                    queue.push(i);
+                }
+            });
+            for _ in 0..NUM_MSGS {
+                queue.pop();
+            }
+        });
+    });
+}
+
+#[bench]
+fn array_queue_mpmc(b: &mut Bencher) {
+    let queue = ArrayQueue::new(CAPACITY);
+    b.iter(|| {
+        scope(|scope| {
+            for _ in 0..NUM_THREADS {
+                scope.spawn(|| {
+                    for i in 0..NUM_MSGS {
+                        queue.push(i);
+                    }
+                });
+                scope.spawn(|| {
+                    for _ in 0..NUM_MSGS {
+                        queue.pop();
+                    }
+                });
+            }
+        });
+    });
+}
+
+#[bench]
+fn seg_queue_spsc(b: &mut Bencher) {
+    let queue = SegQueue::new();
+    b.iter(|| {
+        scope(|scope| {
+            scope.spawn(|| {
+                for i in 0..NUM_MSGS {
+                    queue.push(i);
+                }
+            });
+            for _ in 0..NUM_MSGS {
+                while queue.try_pop().is_none() {}
+            }
+        });
+    });
+}
+
+#[bench]
+fn seg_queue_mpmc(b: &mut Bencher) {
+    let queue = SegQueue::new();
+    b.iter(|| {
+        scope(|scope| {
+            for _ in 0..NUM_THREADS {
+                scope.spawn(|| {
+                    for i in 0..NUM_MSGS {
+                        queue.push(i);
+                    }
+                });
+                scope.spawn(|| {
+                    for _ in 0..NUM_MSGS {
+                        while queue.try_pop().is_none() {}
+                    }
+                });
+            }
+        });
+    });
+}
+
+#[bench]
+fn ms_queue_mpmc(b: &mut Bencher) {
+    let queue = MsQueue::new();
+    b.iter(|| {
+        scope(|scope| {
+            for _ in 0..NUM_THREADS {
+                scope.spawn(|| {
+                    for i in 0..NUM_MSGS {
+                        queue.push(i);
+                    }
+                });
+                scope.spawn(|| {
+                    for _ in 0..NUM_MSGS {
+                        queue.pop();
+                    }
+                });
+            }
+        });
+    });
+}
+
+#[bench]
+fn sync_channel_spsc(b: &mut Bencher) {
+    b.iter(|| {
+        let (tx, rx) = sync_channel(CAPACITY);
+        scope(|scope| {
+            scope.spawn(move || {
+                for i in 0..NUM_MSGS {
+                    tx.send(i).unwrap();
+                }
+            });
+            for _ in 0..NUM_MSGS {
+                rx.recv().unwrap();
+            }
+        });
+    });
+}
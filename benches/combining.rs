@@ -0,0 +1,65 @@
+//! Throughput of FlatCombining against the direct update loop on a hot cell
+
+#![feature(test)]
+
+extern crate test;
+extern crate crossbeam;
+extern crate exclusive_ptr;
+
+use test::Bencher;
+use crossbeam::scope;
+use exclusive_ptr::ExclusiveUsize;
+use exclusive_ptr::ordering::Relaxed;
+use exclusive_ptr::combining::{FlatCombining, Operation};
+
+const NUM_OPS: usize = 10000;
+const NUM_THREADS: usize = 8;
+
+struct Add(usize);
+
+impl Operation<usize> for Add {
+    type Output = ();
+
+    fn apply(&self, val: usize) -> (usize, ()) {
+        (val.wrapping_add(self.0), ())
+    }
+}
+
+#[bench]
+fn flat_combining_add(b: &mut Bencher) {
+    let cell = FlatCombining::new(ExclusiveUsize::new(0));
+    b.iter(|| {
+        scope(|scope| {
+            for _ in 0..NUM_THREADS {
+                scope.spawn(|| {
+                    for i in 0..NUM_OPS {
+                        cell.apply(Add(i));
+                    }
+                });
+            }
+        });
+    });
+}
+
+#[bench]
+fn direct_loop_add(b: &mut Bencher) {
+    let cell = ExclusiveUsize::new(0);
+    b.iter(|| {
+        scope(|scope| {
+            for _ in 0..NUM_THREADS {
+                scope.spawn(|| {
+                    for i in 0..NUM_OPS {
+                        let mut ll = cell.load_linked(Relaxed);
+                        loop {
+                            let val = ll.get().wrapping_add(i);
+                            match ll.store_conditional(val, Relaxed) {
+                                Ok(()) => break,
+                                Err(fail) => ll = fail.into_link(),
+                            }
+                        }
+                    }
+                });
+            }
+        });
+    });
+}
@@ -0,0 +1,90 @@
+//! Throughput of EliminationStack against the plain Treiber stacks
+//!
+//! Every thread pushes and pops in pairs on one shared stack, which keeps
+//! the head as contended as it gets and gives elimination the most pairs.
+
+#![feature(test)]
+
+extern crate test;
+extern crate crossbeam;
+extern crate exclusive_ptr;
+
+use test::Bencher;
+use crossbeam::scope;
+use crossbeam::sync::TreiberStack;
+use exclusive_ptr::collections::{EliminationStack, EliminationConfig, Stack};
+
+const NUM_OPS: usize = 10000;
+const NUM_THREADS: usize = 4;
+
+#[bench]
+fn elimination_stack_pairs(b: &mut Bencher) {
+    let stack = EliminationStack::new();
+    b.iter(|| {
+        scope(|scope| {
+            for _ in 0..NUM_THREADS {
+                scope.spawn(|| {
+                    for i in 0..NUM_OPS {
+                        stack.push(i);
+                        stack.pop();
+                    }
+                });
+            }
+        });
+    });
+}
+
+#[bench]
+fn elimination_stack_pairs_wide(b: &mut Bencher) {
+    let config = EliminationConfig {
+        slots: 2 * NUM_THREADS,
+        timeout_spins: 256,
+    };
+    let stack = EliminationStack::with_config(config);
+    b.iter(|| {
+        scope(|scope| {
+            for _ in 0..NUM_THREADS {
+                scope.spawn(|| {
+                    for i in 0..NUM_OPS {
+                        stack.push(i);
+                        stack.pop();
+                    }
+                });
+            }
+        });
+    });
+}
+
+#[bench]
+fn stack_pairs(b: &mut Bencher) {
+    let stack = Stack::new();
+    b.iter(|| {
+        scope(|scope| {
+            for _ in 0..NUM_THREADS {
+                scope.spawn(|| {
+                    for i in 0..NUM_OPS {
+                        stack.push(i);
+                        stack.pop();
+                    }
+                });
+            }
+        });
+    });
+}
+
+#[bench]
+fn treiber_stack_pairs(b: &mut Bencher) {
+    let stack = TreiberStack::new();
+    b.iter(|| {
+        scope(|scope| {
+            for _ in 0..NUM_THREADS {
+                scope.spawn(|| {
+                    for i in 0..NUM_OPS {
+                        stack.push(i);
+                        stack.pop();
+                    }
+                });
+            }
+        });
+    });
+}
@@ -0,0 +1,108 @@
+//! Padding cells out to their own cache line
+//!
+//! Cells which sit on the same cache line bounce it between the cores writing
+//! them, even though they never touch each other's cell. On LL/SC hardware it
+//! is worse, a store anywhere in the reservation granule of a link breaks it,
+//! so a busy neighbour can make a store_conditional fail over and over.
+//!
+//! x86_64 fetches cache lines in pairs and the big aarch64 and powerpc cores
+//! have 128 byte lines, so those are padded to 128 bytes. The granule can't be
+//! queried portably, so it is the architectural maximum: ARM allows granules
+//! of up to 2KB, although most cores use a single cache line.
+
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+/// The size cells are padded to in order to avoid false sharing
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "powerpc"))]
+pub const CACHE_LINE: usize = 128;
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "powerpc")))]
+pub const CACHE_LINE: usize = 64;
+
+/// The largest range in which a store can break a link of the same core
+#[cfg(any(target_arch = "aarch64", target_arch = "arm"))]
+pub const RESERVATION_GRANULE: usize = 2048;
+#[cfg(not(any(target_arch = "aarch64", target_arch = "arm")))]
+pub const RESERVATION_GRANULE: usize = CACHE_LINE;
+
+// Padding to a granule is never less than padding to a cache line
+const _: () = assert!(RESERVATION_GRANULE >= CACHE_LINE);
+
+/// A value aligned to, and so alone on, its own cache line
+///
+/// Mostly meant for cells, as in CachePadded<ExclusiveUsize>,
+/// which can still be linked through the deref
+#[cfg_attr(any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "powerpc"),
+           repr(align(128)))]
+#[cfg_attr(not(any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "powerpc")),
+           repr(align(64)))]
+#[derive(Default)]
+pub struct CachePadded<T> {
+    value: T,
+}
+
+impl<T> CachePadded<T> {
+
+    pub const fn new(value: T) -> CachePadded<T> {
+        CachePadded {
+            value,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for CachePadded<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<T> From<T> for CachePadded<T> {
+    fn from(value: T) -> CachePadded<T> {
+        CachePadded::new(value)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for CachePadded<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CachePadded").field("value", &self.value).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::mem;
+    use std::sync::atomic::Ordering::Relaxed;
+    use ordering;
+    use ExclusiveUsize;
+
+    static PADDED: CachePadded<ExclusiveUsize> = CachePadded::new(ExclusiveUsize::new(1));
+
+    #[test]
+    fn test_padded() {
+        assert_eq!(mem::align_of::<CachePadded<ExclusiveUsize>>(), CACHE_LINE);
+        assert_eq!(mem::size_of::<CachePadded<ExclusiveUsize>>(), CACHE_LINE);
+
+        let ll = PADDED.load_linked(ordering::Relaxed);
+        assert!(ll.try_store_conditional(2, ordering::Relaxed));
+        assert_eq!(PADDED.load(Relaxed), 2);
+
+        let cells: Vec<_> = (0..4).map(|i| CachePadded::new(ExclusiveUsize::new(i))).collect();
+        let first = &*cells[0] as *const _ as usize;
+        let second = &*cells[1] as *const _ as usize;
+        assert_eq!(second - first, CACHE_LINE);
+        assert_eq!(cells[3].load(Relaxed), 3);
+    }
+}
@@ -0,0 +1,242 @@
+//! A bounded queue on a ring buffer
+//!
+//! Each slot carries a sequence number telling which lap of the ring it is
+//! ready for. A push claims position pos by moving the tail from pos to pos + 1
+//! once the slot's sequence is pos, then publishes the value by setting the
+//! sequence to pos + 1. A pop claims it the same way through the head, and hands
+//! the slot to the next lap by setting the sequence to pos + capacity.
+//!
+//! The head and tail are ExclusiveUsizes, so a claim only succeeds if the index
+//! hasn't moved since the slot was checked. Nothing is allocated after new.
+
+use std::thread;
+use std::cell::UnsafeCell;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::{Acquire, Release, SeqCst};
+
+use ordering;
+use ExclusiveUsize;
+
+struct Slot<T> {
+    seq: AtomicUsize,
+    val: UnsafeCell<Option<T>>,
+}
+
+pub struct ArrayQueue<T> {
+    head: ExclusiveUsize,
+    tail: ExclusiveUsize,
+    slots: Box<[Slot<T>]>,
+}
+
+impl<T> ArrayQueue<T> {
+
+    /// Creates a queue holding up to capacity values
+    ///
+    /// Panics if capacity is 0
+    pub fn new(capacity: usize) -> ArrayQueue<T> {
+        assert!(capacity > 0, "capacity must be non-zero");
+        let slots: Vec<_> = (0..capacity).map(|i| Slot {
+            seq: AtomicUsize::new(i),
+            val: UnsafeCell::new(None),
+        }).collect();
+        ArrayQueue {
+            head: ExclusiveUsize::new(0),
+            tail: ExclusiveUsize::new(0),
+            slots: slots.into_boxed_slice(),
+        }
+    }
+
+    /// Pushes the value, or hands it back if the queue is full
+    pub fn try_push(&self, val: T) -> Result<(), T> {
+        let mut ll = self.tail.load_linked(ordering::Relaxed);
+        loop {
+            let pos = ll.get();
+            let slot = &self.slots[pos % self.slots.len()];
+            let seq = slot.seq.load(Acquire);
+            let diff = seq.wrapping_sub(pos) as isize;
+            if diff == 0 {
+                match ll.store_conditional(pos.wrapping_add(1), ordering::Relaxed) {
+                    Ok(()) => {
+                        unsafe { *slot.val.get() = Some(val) };
+                        slot.seq.store(pos.wrapping_add(1), Release);
+                        return Ok(());
+                    },
+                    Err(fail) => ll = fail.into_link(),
+                }
+            } else if diff < 0 {
+                // The slot still holds the value from the last lap
+                return Err(val);
+            } else {
+                ll = self.tail.load_linked(ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Pops a value, or returns None if the queue is empty
+    pub fn try_pop(&self) -> Option<T> {
+        let mut ll = self.head.load_linked(ordering::Relaxed);
+        loop {
+            let pos = ll.get();
+            let slot = &self.slots[pos % self.slots.len()];
+            let seq = slot.seq.load(Acquire);
+            let diff = seq.wrapping_sub(pos.wrapping_add(1)) as isize;
+            if diff == 0 {
+                match ll.store_conditional(pos.wrapping_add(1), ordering::Relaxed) {
+                    Ok(()) => {
+                        let val = unsafe { (*slot.val.get()).take() };
+                        slot.seq.store(pos.wrapping_add(self.slots.len()), Release);
+                        return val;
+                    },
+                    Err(fail) => ll = fail.into_link(),
+                }
+            } else if diff < 0 {
+                // The slot hasn't been pushed to for this lap yet
+                return None;
+            } else {
+                ll = self.head.load_linked(ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Pushes the value, waiting for room if the queue is full
+    pub fn push(&self, mut val: T) {
+        loop {
+            match self.try_push(val) {
+                Ok(()) => return,
+                Err(v) => val = v,
+            }
+            thread::yield_now();
+        }
+    }
+
+    /// Pops a value, waiting for one if the queue is empty
+    pub fn pop(&self) -> T {
+        loop {
+            if let Some(val) = self.try_pop() {
+                return val;
+            }
+            thread::yield_now();
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Returns the number of values in the queue
+    ///
+    /// This counts values which are still being pushed or popped
+    pub fn len(&self) -> usize {
+        loop {
+            let tail = self.tail.load(SeqCst);
+            let head = self.head.load(SeqCst);
+            // Retry until the pair was read from a single point in time
+            if self.tail.load(SeqCst) == tail {
+                return tail.wrapping_sub(head);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == self.capacity()
+    }
+}
+
+unsafe impl<T: Send> Send for ArrayQueue<T> {}
+unsafe impl<T: Send> Sync for ArrayQueue<T> {}
+
+#[cfg(test)]
+mod test {
+    extern crate crossbeam;
+    use self::crossbeam::scope;
+    use super::*;
+
+    #[test]
+    fn test_push_pop() {
+        let queue = ArrayQueue::new(4);
+        assert_eq!(queue.capacity(), 4);
+        assert!(queue.is_empty());
+        assert_eq!(queue.try_pop(), None);
+        for i in 0..4 {
+            assert!(queue.try_push(vec![i]).is_ok());
+        }
+        assert!(queue.is_full());
+        assert_eq!(queue.try_push(vec![4]), Err(vec![4]));
+        assert_eq!(queue.len(), 4);
+        for i in 0..4 {
+            assert_eq!(queue.try_pop(), Some(vec![i]));
+        }
+        assert_eq!(queue.try_pop(), None);
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn test_laps() {
+        let queue = ArrayQueue::new(2);
+        for i in 0..1000 {
+            queue.push(i);
+            queue.push(i + 1);
+            assert_eq!(queue.try_push(0), Err(0));
+            assert_eq!(queue.pop(), i);
+            assert_eq!(queue.pop(), i + 1);
+        }
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_drop() {
+        let queue = ArrayQueue::new(8);
+        let val = ::std::sync::Arc::new(());
+        for _ in 0..5 {
+            queue.push(val.clone());
+        }
+        queue.pop();
+        drop(queue);
+        assert_eq!(::std::sync::Arc::strong_count(&val), 1);
+    }
+
+    #[test]
+    fn test_mpmc() {
+        let num_run: usize = 20000;
+        let num_threads = 4;
+        let queue = ArrayQueue::new(16);
+
+        let popped: Vec<Vec<(usize, usize)>> = scope(|scope| {
+            for p in 0..num_threads {
+                let queue = &queue;
+                scope.spawn(move || {
+                    for i in 0..num_run {
+                        queue.push((p, i));
+                    }
+                });
+            }
+            let handles: Vec<_> = (0..num_threads).map(|_| {
+                let queue = &queue;
+                scope.spawn(move || {
+                    let mut last = vec![None; num_threads];
+                    let mut popped = Vec::with_capacity(num_run);
+                    for _ in 0..num_run {
+                        let (p, i) = queue.pop();
+                        assert!(last[p] < Some(i));
+                        last[p] = Some(i);
+                        popped.push((p, i));
+                    }
+                    popped
+                })
+            }).collect();
+            handles.into_iter().map(|h| h.join()).collect()
+        });
+
+        let mut all: Vec<_> = popped.into_iter().flatten().collect();
+        all.sort();
+        let expected: Vec<_> = (0..num_threads)
+            .flat_map(|p| (0..num_run).map(move |i| (p, i)))
+            .collect();
+        assert_eq!(all, expected);
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,375 @@
+//! A Chase-Lev work-stealing deque
+//!
+//! The owner pushes and pops at the bottom, and thieves steal from the top.
+//! Only the owner writes bottom, so it only needs a store_conditional on top
+//! when it races the thieves for the last value. A thief links top before it
+//! reads the value there, and its store_conditional fails if anyone took a
+//! value from the top since, in which case the value it read isn't its to keep.
+//!
+//! The values sit in a circular buffer which the owner replaces with one twice
+//! as large when it fills up. Thieves may still be reading the old buffer, so
+//! it is retired to the epoch instead of being freed right away.
+
+use std::cmp;
+use std::mem;
+use std::ptr;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::sync::atomic::fence;
+use std::sync::atomic::Ordering::{Relaxed, Acquire, Release, SeqCst};
+
+use ordering;
+use epoch;
+use {ExclusivePtr, ExclusiveIsize};
+
+const MIN_CAP: usize = 16;
+
+struct Buffer<T> {
+    // Always empty, it only owns the memory
+    storage: Vec<T>,
+    ptr: *mut T,
+}
+
+impl<T> Buffer<T> {
+
+    fn new(cap: usize) -> *mut Buffer<T> {
+        let mut storage = Vec::with_capacity(cap);
+        let ptr = storage.as_mut_ptr();
+        Box::into_raw(Box::new(Buffer {
+            storage,
+            ptr,
+        }))
+    }
+
+    fn cap(&self) -> usize {
+        self.storage.capacity()
+    }
+
+    unsafe fn at(&self, index: isize) -> *mut T {
+        self.ptr.add(index as usize & (self.cap() - 1))
+    }
+
+    unsafe fn read(&self, index: isize) -> T {
+        ptr::read(self.at(index))
+    }
+
+    unsafe fn write(&self, index: isize, val: T) {
+        ptr::write(self.at(index), val)
+    }
+}
+
+struct Inner<T> {
+    top: ExclusiveIsize,
+    bottom: ExclusiveIsize,
+    buffer: ExclusivePtr<Buffer<T>>,
+}
+
+/// The owner's end of the deque
+///
+/// Only one thread can push and pop, so this can be sent but not shared
+pub struct Deque<T> {
+    inner: Arc<Inner<T>>,
+    marker: PhantomData<*mut ()>,
+}
+
+/// A thief's end of the deque, which can be cloned and shared freely
+pub struct Stealer<T> {
+    inner: Arc<Inner<T>>,
+}
+
+/// The result of a steal
+#[derive(Debug, PartialEq, Eq)]
+pub enum Stolen<T> {
+    /// The deque was empty
+    Empty,
+
+    /// Another thread took the value first, so the deque may not be empty
+    Abort,
+
+    Data(T),
+}
+
+impl<T> Deque<T> {
+
+    pub fn new() -> Deque<T> {
+        Deque {
+            inner: Arc::new(Inner {
+                top: ExclusiveIsize::new(0),
+                bottom: ExclusiveIsize::new(0),
+                buffer: ExclusivePtr::new(Buffer::new(MIN_CAP)),
+            }),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn stealer(&self) -> Stealer<T> {
+        Stealer { inner: self.inner.clone() }
+    }
+
+    /// Pushes the value at the bottom
+    pub fn push(&self, val: T) {
+        let inner = &*self.inner;
+        let b = inner.bottom.load(Relaxed);
+        let t = inner.top.load(Acquire);
+        let mut buffer = inner.buffer.load(Relaxed);
+        unsafe {
+            if b - t >= (*buffer).cap() as isize {
+                buffer = self.grow(buffer, t, b);
+            }
+            (*buffer).write(b, val);
+        }
+        inner.bottom.store_direct(b + 1, Release);
+    }
+
+    /// Pops the value at the bottom, the one pushed last
+    pub fn pop(&self) -> Option<T> {
+        let inner = &*self.inner;
+        let b = inner.bottom.load(Relaxed) - 1;
+        let buffer = inner.buffer.load(Relaxed);
+        // Thieves which read top after this see the value as taken
+        inner.bottom.store_direct(b, Relaxed);
+        fence(SeqCst);
+        let t = inner.top.load(Relaxed);
+        if t > b {
+            inner.bottom.store_direct(b + 1, Relaxed);
+            return None;
+        }
+        let val = unsafe { (*buffer).read(b) };
+        if t < b {
+            return Some(val);
+        }
+        // The last value, which a thief may be taking as well
+        let won = take_top(&inner.top, t);
+        inner.bottom.store_direct(b + 1, Relaxed);
+        match won {
+            true => Some(val),
+            false => {
+                mem::forget(val);
+                None
+            },
+        }
+    }
+
+    /// Returns the number of values, which may be stale if thieves are stealing
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Moves the values into a buffer twice as large
+    unsafe fn grow(&self, old: *mut Buffer<T>, t: isize, b: isize) -> *mut Buffer<T> {
+        let guard = epoch::pin();
+        let new = Buffer::new((*old).cap() * 2);
+        for i in t..b {
+            ptr::copy_nonoverlapping((*old).at(i), (*new).at(i), 1);
+        }
+        self.inner.buffer.store_direct(new, Release);
+        // The values were moved out, so dropping the buffer only frees its storage
+        guard.retire(old);
+        new
+    }
+}
+
+impl<T> Default for Deque<T> {
+    fn default() -> Deque<T> {
+        Deque::new()
+    }
+}
+
+// Takes the value at t from the top, unless someone else took it first
+fn take_top(top: &ExclusiveIsize, t: isize) -> bool {
+    let mut ll = top.load_linked(ordering::Relaxed);
+    while ll.get() == t {
+        match ll.store_conditional(t + 1, ordering::SeqCst) {
+            Ok(()) => return true,
+            Err(fail) => ll = fail.into_link(),
+        }
+    }
+    false
+}
+
+impl<T> Stealer<T> {
+
+    /// Steals the value at the top, the oldest one
+    pub fn steal(&self) -> Stolen<T> {
+        let inner = &*self.inner;
+        let _guard = epoch::pin();
+        let mut ll = inner.top.load_linked(ordering::Acquire);
+        let t = ll.get();
+        fence(SeqCst);
+        let b = inner.bottom.load(Acquire);
+        if t >= b {
+            return Stolen::Empty;
+        }
+        let buffer = inner.buffer.load(Acquire);
+        // The owner may overwrite the slot once the value is taken,
+        // so it's only a copy until the store_conditional succeeds
+        let val = unsafe { (*buffer).read(t) };
+        loop {
+            match ll.store_conditional(t + 1, ordering::SeqCst) {
+                Ok(()) => return Stolen::Data(val),
+                Err(fail) => ll = fail.into_link(),
+            }
+            if ll.get() != t {
+                mem::forget(val);
+                return Stolen::Abort;
+            }
+        }
+    }
+
+    /// Returns the number of values, which may be stale by the time it returns
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Clone for Stealer<T> {
+    fn clone(&self) -> Stealer<T> {
+        Stealer { inner: self.inner.clone() }
+    }
+}
+
+impl<T> Inner<T> {
+
+    fn len(&self) -> usize {
+        let t = self.top.load(SeqCst);
+        let b = self.bottom.load(SeqCst);
+        // A pop or steal in flight can leave top past bottom for a moment
+        cmp::max(b - t, 0) as usize
+    }
+}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        let t = self.top.load(Relaxed);
+        let b = self.bottom.load(Relaxed);
+        let buffer = self.buffer.load(Relaxed);
+        unsafe {
+            for i in t..b {
+                drop((*buffer).read(i));
+            }
+            drop(Box::from_raw(buffer));
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for Deque<T> {}
+unsafe impl<T: Send> Send for Stealer<T> {}
+unsafe impl<T: Send> Sync for Stealer<T> {}
+
+#[cfg(test)]
+mod test {
+    use crossbeam::scope;
+    use std::sync::atomic::AtomicBool;
+    use super::*;
+
+    #[test]
+    fn test_push_pop() {
+        let deque = Deque::new();
+        let stealer = deque.stealer();
+        assert_eq!(deque.pop(), None);
+        assert_eq!(stealer.steal(), Stolen::Empty);
+        for i in 0..4 {
+            deque.push(i);
+        }
+        assert_eq!(deque.len(), 4);
+        assert_eq!(deque.pop(), Some(3));
+        assert_eq!(stealer.steal(), Stolen::Data(0));
+        assert_eq!(deque.pop(), Some(2));
+        assert_eq!(stealer.steal(), Stolen::Data(1));
+        assert_eq!(deque.pop(), None);
+        assert!(stealer.is_empty());
+    }
+
+    #[test]
+    fn test_grow() {
+        let deque = Deque::new();
+        let stealer = deque.stealer();
+        for i in 0..1000 {
+            deque.push(vec![i]);
+            if i % 3 == 0 {
+                assert_eq!(stealer.steal(), Stolen::Data(vec![i / 3]));
+            }
+        }
+        for i in (334..1000).rev() {
+            assert_eq!(deque.pop(), Some(vec![i]));
+        }
+        assert!(deque.is_empty());
+    }
+
+    #[test]
+    fn test_drop() {
+        let val = Arc::new(());
+        {
+            let deque = Deque::new();
+            let stealer = deque.stealer();
+            for _ in 0..100 {
+                deque.push(val.clone());
+            }
+            deque.pop();
+            stealer.steal();
+            drop(deque);
+            assert_eq!(Arc::strong_count(&val), 99);
+        }
+        assert_eq!(Arc::strong_count(&val), 1);
+    }
+
+    // Thieves steal as fast as they can while the owner pushes in bursts
+    // and pops, so the last value is fought over and the buffer grows under them
+    #[test]
+    fn test_mt_steal() {
+        let num_run: usize = 100000;
+        let num_thieves = 4;
+        let deque: Deque<Box<usize>> = Deque::new();
+        let done = AtomicBool::new(false);
+
+        let (popped, stolen): (Vec<usize>, Vec<Vec<usize>>) = scope(|scope| {
+            let handles: Vec<_> = (0..num_thieves).map(|_| {
+                let stealer = deque.stealer();
+                let done = &done;
+                scope.spawn(move || {
+                    let mut stolen = Vec::new();
+                    loop {
+                        match stealer.steal() {
+                            Stolen::Data(v) => stolen.push(*v),
+                            Stolen::Abort => (),
+                            Stolen::Empty => if done.load(SeqCst) {
+                                return stolen;
+                            },
+                        }
+                    }
+                })
+            }).collect();
+
+            let mut popped = Vec::new();
+            for i in 0..num_run {
+                deque.push(Box::new(i));
+                if i % 1000 == 999 {
+                    popped.extend((0..500).filter_map(|_| deque.pop()).map(|v| *v));
+                } else if i % 5 == 0 {
+                    popped.extend(deque.pop().map(|v| *v));
+                }
+            }
+            while let Some(v) = deque.pop() {
+                popped.push(*v);
+            }
+            done.store(true, SeqCst);
+            (popped, handles.into_iter().map(|h| h.join()).collect())
+        });
+
+        for s in &stolen {
+            // A thief sees values in the order they were pushed
+            assert!(s.windows(2).all(|w| w[0] < w[1]));
+        }
+        let mut all: Vec<_> = popped.into_iter().chain(stolen.into_iter().flatten()).collect();
+        all.sort();
+        assert_eq!(all, (0..num_run).collect::<Vec<_>>());
+    }
+}
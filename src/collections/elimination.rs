@@ -0,0 +1,245 @@
+//! A Treiber stack with elimination backoff
+//!
+//! A push or pop which loses the store_conditional on the head backs off to
+//! a random slot of the elimination array instead of retrying right away.
+//! A pusher offers its node in an empty slot and waits for a while, and a
+//! popper which finds an offer takes it by marking the slot, so the pair
+//! cancels out without touching the head at all. Once the wait is over the
+//! pusher clears the slot, taking the node back if nobody marked it.
+//!
+//! A marked slot is left alone by everyone but the pusher who filled it, so
+//! the node can't be recycled and offered in the same slot before the pusher
+//! has seen that it was taken.
+
+use std::ptr;
+use std::cell::{Cell, UnsafeCell};
+use std::sync::atomic::{AtomicUsize, AtomicPtr};
+use std::sync::atomic::Ordering::Relaxed;
+
+use ordering;
+use ExclusivePtr;
+use super::stack::{Node, push_node, pop_node, free_list};
+
+static NEXT_SEED: AtomicUsize = AtomicUsize::new(1);
+
+thread_local!(static SEED: Cell<u32> =
+    Cell::new((NEXT_SEED.fetch_add(1, Relaxed) as u32).wrapping_mul(0x9e37_79b9) | 1));
+
+fn random_slot(num_slots: usize) -> usize {
+    SEED.with(|seed| {
+        let mut x = seed.get();
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        seed.set(x);
+        x as usize % num_slots
+    })
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct EliminationConfig {
+    /// The number of exchange slots, which should grow with the number of threads
+    pub slots: usize,
+
+    /// How many times a pusher checks its offer before taking it back
+    pub timeout_spins: usize,
+}
+
+impl Default for EliminationConfig {
+    fn default() -> EliminationConfig {
+        EliminationConfig {
+            slots: 8,
+            timeout_spins: 64,
+        }
+    }
+}
+
+pub struct EliminationStack<T> {
+    head: ExclusivePtr<Node<T>>,
+    free: ExclusivePtr<Node<T>>,
+    slots: Box<[ExclusivePtr<Node<T>>]>,
+    timeout_spins: usize,
+    eliminated: AtomicUsize,
+}
+
+impl<T> EliminationStack<T> {
+
+    pub fn new() -> EliminationStack<T> {
+        EliminationStack::with_config(EliminationConfig::default())
+    }
+
+    /// Panics if config.slots is 0
+    pub fn with_config(config: EliminationConfig) -> EliminationStack<T> {
+        assert!(config.slots > 0, "there must be at least one slot");
+        EliminationStack {
+            head: ExclusivePtr::NULL,
+            free: ExclusivePtr::NULL,
+            slots: (0..config.slots).map(|_| ExclusivePtr::NULL).collect::<Vec<_>>()
+                                    .into_boxed_slice(),
+            timeout_spins: config.timeout_spins,
+            eliminated: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn push(&self, val: T) {
+        let mut node = pop_node(&self.free);
+        if node.is_null() {
+            node = Box::into_raw(Box::new(Node {
+                val: UnsafeCell::new(None),
+                next: AtomicPtr::new(ptr::null_mut()),
+            }));
+        }
+        unsafe { *(*node).val.get() = Some(val) };
+        loop {
+            let ll = self.head.load_linked(ordering::Relaxed);
+            unsafe { (*node).next.store(ll.get(), Relaxed) };
+            if ll.try_store_conditional(node, ordering::Release) || self.offer(node) {
+                return;
+            }
+        }
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        loop {
+            let ll = self.head.load_linked(ordering::Acquire);
+            let node = ll.get();
+            if node.is_null() {
+                return None;
+            }
+            let next = unsafe { (*node).next.load(Relaxed) };
+            if ll.try_store_conditional(next, ordering::Relaxed) {
+                return unsafe { Some(self.recycle(node)) };
+            }
+            let node = self.take_offer();
+            if !node.is_null() {
+                return unsafe { Some(self.recycle(node)) };
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Relaxed).is_null()
+    }
+
+    /// The number of push and pop pairs which cancelled out in the elimination array
+    pub fn eliminated(&self) -> usize {
+        self.eliminated.load(Relaxed)
+    }
+
+    // Offers the node to poppers, returning whether one took it
+    fn offer(&self, node: *mut Node<T>) -> bool {
+        let slot = &self.slots[random_slot(self.slots.len())];
+        let ll = slot.load_linked(ordering::Relaxed);
+        if !ll.get().is_null() || !ll.try_store_conditional(node, ordering::Release) {
+            return false;
+        }
+        for _ in 0..self.timeout_spins {
+            if slot.load_marked(Relaxed).1 {
+                break;
+            }
+        }
+        // Only this thread can empty the slot, so this only retries spurious failures
+        let mut ll = slot.load_linked(ordering::Acquire);
+        loop {
+            let taken = ll.is_marked();
+            match ll.store_conditional(ptr::null_mut(), ordering::Relaxed) {
+                Ok(()) => return taken,
+                Err(fail) => ll = fail.into_link(),
+            }
+        }
+    }
+
+    // Takes a node offered by a pusher, or returns null if there was none
+    fn take_offer(&self) -> *mut Node<T> {
+        let slot = &self.slots[random_slot(self.slots.len())];
+        let ll = slot.load_linked(ordering::Acquire);
+        let (node, marked) = ll.get_marked();
+        if node.is_null() || marked || !ll.try_store_conditional_marked(node, true, ordering::Relaxed) {
+            return ptr::null_mut();
+        }
+        self.eliminated.fetch_add(1, Relaxed);
+        node
+    }
+
+    // Takes the value out of a node which is no longer reachable
+    unsafe fn recycle(&self, node: *mut Node<T>) -> T {
+        let val = (*(*node).val.get()).take().unwrap();
+        push_node(&self.free, node);
+        val
+    }
+}
+
+impl<T> Default for EliminationStack<T> {
+    fn default() -> EliminationStack<T> {
+        EliminationStack::new()
+    }
+}
+
+impl<T> Drop for EliminationStack<T> {
+    fn drop(&mut self) {
+        // Pushers empty their slots before they return, so every node is on a list
+        unsafe {
+            free_list(self.head.load(Relaxed));
+            free_list(self.free.load(Relaxed));
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for EliminationStack<T> {}
+unsafe impl<T: Send> Sync for EliminationStack<T> {}
+
+#[cfg(test)]
+mod test {
+    use crossbeam::scope;
+    use super::*;
+
+    #[test]
+    fn test_push_pop() {
+        let stack = EliminationStack::new();
+        assert_eq!(stack.pop(), None);
+        for i in 0..10 {
+            stack.push(vec![i]);
+        }
+        for i in (0..10).rev() {
+            assert_eq!(stack.pop(), Some(vec![i]));
+        }
+        assert_eq!(stack.pop(), None);
+        assert!(stack.is_empty());
+        assert_eq!(stack.eliminated(), 0);
+    }
+
+    // A single slot with a long wait sends nearly every contended push and pop
+    // through the same exchange, where a lost or doubled value would show up
+    #[test]
+    fn test_mt_eliminate() {
+        let num_run: usize = 20000;
+        let num_threads = 4;
+        let config = EliminationConfig {
+            slots: 1,
+            timeout_spins: 1000,
+        };
+        let stack = EliminationStack::with_config(config);
+
+        let popped: Vec<Vec<usize>> = scope(|scope| {
+            let handles: Vec<_> = (0..num_threads).map(|t| {
+                let stack = &stack;
+                scope.spawn(move || {
+                    let mut popped = Vec::new();
+                    for i in 0..num_run {
+                        stack.push(vec![t * num_run + i; 4]);
+                        let v = stack.pop().unwrap();
+                        assert!(v.iter().all(|x| *x == v[0]));
+                        popped.push(v[0]);
+                    }
+                    popped
+                })
+            }).collect();
+            handles.into_iter().map(|h| h.join()).collect()
+        });
+
+        let mut all: Vec<usize> = popped.into_iter().flatten().collect();
+        all.sort();
+        assert_eq!(all, (0..num_threads * num_run).collect::<Vec<_>>());
+        assert!(stack.is_empty());
+    }
+}
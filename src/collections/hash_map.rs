@@ -0,0 +1,493 @@
+//! A split-ordered lock-free hash map
+//!
+//! Every entry lives in one Harris-Michael list, sorted by the bit reversed
+//! hash. Buckets are pointers to dummy nodes in that list, and in this order
+//! doubling the number of buckets only splits each bucket's run of nodes in
+//! two, so growing the table is a single store_conditional on the bucket count.
+//! A new bucket's dummy is linked the first time the bucket is used, starting
+//! from the dummy of its parent, the bucket it was split from.
+//!
+//! The bucket array is a directory of segments which double in size, so it
+//! never has to be copied. Dummies are never removed, and removed entries are
+//! unlinked and retired to the epoch like the ones of List. As there, links
+//! and unlinks go through the link taken on the predecessor when the node
+//! was read, so they fail if it was written at all in between.
+
+use std::ptr;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::marker::PhantomData;
+use std::mem;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::{Relaxed, Acquire, Release};
+
+use ordering;
+use epoch::{self, Guard};
+use {ExclusivePtr, ExclusiveUsize, LinkedPtr};
+
+const SEGMENTS: usize = mem::size_of::<usize>() * 8;
+
+// The most entries per bucket before the bucket count is doubled
+const LOAD_FACTOR: usize = 2;
+
+const MIN_BUCKETS: usize = 2;
+
+// Bucket indices keep the top bit clear, so dummy keys are always even
+const MAX_BUCKETS: usize = 1 << (SEGMENTS - 2);
+
+struct Node<K, V> {
+    // The reversed hash, with the low bit set for entries
+    so_key: usize,
+    // None for dummies
+    entry: Option<(K, V)>,
+    next: ExclusivePtr<Node<K, V>>,
+}
+
+type Bucket<K, V> = ExclusivePtr<Node<K, V>>;
+
+pub struct HashMap<K, V, S = RandomState> {
+    // Segment 0 holds bucket 0 and segment i buckets 2^(i-1) to 2^i
+    segments: [ExclusivePtr<Bucket<K, V>>; SEGMENTS],
+    buckets: ExclusiveUsize,
+    len: AtomicUsize,
+    hasher: S,
+    marker: PhantomData<Box<Node<K, V>>>,
+}
+
+fn entry_key(hash: usize) -> usize {
+    hash.reverse_bits() | 1
+}
+
+fn dummy_key(bucket: usize) -> usize {
+    bucket.reverse_bits()
+}
+
+// The bucket with the top bit cleared, which this one was split from
+fn parent(bucket: usize) -> usize {
+    bucket & !(1 << (SEGMENTS - 1 - bucket.leading_zeros() as usize))
+}
+
+fn segment_of(bucket: usize) -> (usize, usize) {
+    match bucket {
+        0 => (0, 0),
+        _ => {
+            let segment = SEGMENTS - bucket.leading_zeros() as usize;
+            (segment, bucket - (1 << (segment - 1)))
+        },
+    }
+}
+
+fn segment_len(segment: usize) -> usize {
+    match segment {
+        0 => 1,
+        _ => 1 << (segment - 1),
+    }
+}
+
+// Only the thread which unlinked a node may retire it
+unsafe fn retire<K, V>(node: *mut Node<K, V>, guard: &Guard) {
+    guard.retire(node);
+}
+
+impl<K: Hash + Eq, V> HashMap<K, V, RandomState> {
+
+    pub fn new() -> HashMap<K, V, RandomState> {
+        HashMap::with_hasher(RandomState::new())
+    }
+
+    /// Creates a map with enough buckets for capacity entries
+    pub fn with_capacity(capacity: usize) -> HashMap<K, V, RandomState> {
+        HashMap::with_capacity_and_hasher(capacity, RandomState::new())
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> HashMap<K, V, S> {
+
+    pub fn with_hasher(hasher: S) -> HashMap<K, V, S> {
+        HashMap::with_capacity_and_hasher(0, hasher)
+    }
+
+    pub fn with_capacity_and_hasher(capacity: usize, hasher: S) -> HashMap<K, V, S> {
+        let buckets = (capacity / LOAD_FACTOR).next_power_of_two();
+        let map = HashMap {
+            segments: [ExclusivePtr::NULL; SEGMENTS],
+            buckets: ExclusiveUsize::new(buckets.clamp(MIN_BUCKETS, MAX_BUCKETS)),
+            len: AtomicUsize::new(0),
+            hasher,
+            marker: PhantomData,
+        };
+        // Every other dummy is linked after the one of bucket 0
+        let head = Box::into_raw(Box::new(Node {
+            so_key: dummy_key(0),
+            entry: None,
+            next: ExclusivePtr::NULL,
+        }));
+        map.bucket_cell(0).store_direct(head, Relaxed);
+        map
+    }
+
+    /// Inserts the entry if the key isn't in the map yet, returning whether it was
+    pub fn insert(&self, key: K, val: V) -> bool {
+        let guard = epoch::pin();
+        let hash = self.hash(&key);
+        let node = Box::into_raw(Box::new(Node {
+            so_key: entry_key(hash),
+            entry: Some((key, val)),
+            next: ExclusivePtr::NULL,
+        }));
+        // Counted before it's linked, so a remove can't take the count below zero
+        let len = self.len.fetch_add(1, Relaxed) + 1;
+        unsafe {
+            let start = self.bucket(hash, &guard);
+            let key = (*node).entry.as_ref().map(|e| &e.0);
+            loop {
+                let (prev, cur, found) = self.find(start, (*node).so_key, key, &guard);
+                if found {
+                    self.len.fetch_sub(1, Relaxed);
+                    drop(Box::from_raw(node));
+                    return false;
+                }
+                // Nobody else can see the node yet
+                (*node).next.store_direct(cur, Relaxed);
+                if prev.try_store_conditional(node, ordering::Release) {
+                    break;
+                }
+            }
+        }
+        let ll = self.buckets.load_linked(ordering::Relaxed);
+        let buckets = ll.get();
+        // Losing the race is fine, whoever won grew it
+        if len > buckets * LOAD_FACTOR && buckets < MAX_BUCKETS {
+            ll.try_store_conditional(buckets * 2, ordering::Relaxed);
+        }
+        true
+    }
+
+    /// Removes the key, returning whether it was in the map
+    pub fn remove(&self, key: &K) -> bool {
+        let guard = epoch::pin();
+        let hash = self.hash(key);
+        unsafe {
+            let start = self.bucket(hash, &guard);
+            loop {
+                let (prev, cur, found) = self.find(start, entry_key(hash), Some(key), &guard);
+                if !found {
+                    return false;
+                }
+                let ll = (*cur).next.load_linked(ordering::Acquire);
+                let (next, marked) = ll.get_marked();
+                // Someone else is removing it, so find again to help and see who won
+                if marked || !ll.try_store_conditional_marked(next, true, ordering::Relaxed) {
+                    continue;
+                }
+                self.len.fetch_sub(1, Relaxed);
+                if prev.try_store_conditional(next, ordering::Release) {
+                    retire(cur, &guard);
+                } else {
+                    // Let a traversal unlink it instead
+                    self.find(start, entry_key(hash), Some(key), &guard);
+                }
+                return true;
+            }
+        }
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        let guard = epoch::pin();
+        self.get(key, &guard).is_some()
+    }
+
+    /// Returns the value for the key, valid for the life of the guard
+    pub fn get<'g>(&'g self, key: &K, guard: &'g Guard) -> Option<&'g V> {
+        let hash = self.hash(key);
+        unsafe {
+            let start = self.bucket(hash, guard);
+            match self.find(start, entry_key(hash), Some(key), guard) {
+                (_, cur, true) => (*cur).entry.as_ref().map(|e| &e.1),
+                _ => None,
+            }
+        }
+    }
+
+    /// Returns the number of entries, which may be stale by the time it returns
+    pub fn len(&self) -> usize {
+        self.len.load(Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn hasher(&self) -> &S {
+        &self.hasher
+    }
+
+    fn hash(&self, key: &K) -> usize {
+        self.hasher.hash_one(key) as usize
+    }
+
+    // Returns the cell for the bucket, allocating its segment if needed
+    fn bucket_cell(&self, bucket: usize) -> &Bucket<K, V> {
+        let (segment, offset) = segment_of(bucket);
+        let cell = &self.segments[segment];
+        let mut table = cell.load(Acquire);
+        if table.is_null() {
+            let len = segment_len(segment);
+            let fresh: Vec<Bucket<K, V>> = (0..len).map(|_| ExclusivePtr::NULL).collect();
+            let fresh = Box::into_raw(fresh.into_boxed_slice()) as *mut Bucket<K, V>;
+            table = loop {
+                let ll = cell.load_linked(ordering::Acquire);
+                if !ll.get().is_null() {
+                    unsafe { drop(Box::from_raw(ptr::slice_from_raw_parts_mut(fresh, len))) };
+                    break ll.get();
+                }
+                if ll.try_store_conditional(fresh, ordering::Release) {
+                    break fresh;
+                }
+            };
+        }
+        unsafe { &*table.add(offset) }
+    }
+
+    // Returns the link after the dummy of the hash's bucket, linking the dummy if needed
+    unsafe fn bucket<'g>(&'g self, hash: usize, guard: &'g Guard) -> &'g Bucket<K, V> {
+        let bucket = hash & (self.buckets.load(Relaxed) - 1);
+        self.init_bucket(bucket, guard)
+    }
+
+    unsafe fn init_bucket<'g>(&'g self, bucket: usize, guard: &'g Guard) -> &'g Bucket<K, V> {
+        let cell = self.bucket_cell(bucket);
+        let dummy = cell.load(Acquire);
+        if !dummy.is_null() {
+            return &(*dummy).next;
+        }
+        let start = self.init_bucket(parent(bucket), guard);
+        let node = Box::into_raw(Box::new(Node {
+            so_key: dummy_key(bucket),
+            entry: None,
+            next: ExclusivePtr::NULL,
+        }));
+        let dummy = loop {
+            let (prev, cur, found) = self.find(start, (*node).so_key, None, guard);
+            // Another thread linked it first
+            if found {
+                drop(Box::from_raw(node));
+                break cur;
+            }
+            (*node).next.store_direct(cur, Relaxed);
+            if prev.try_store_conditional(node, ordering::Release) {
+                break node;
+            }
+        };
+        // Everyone who gets here stores the same dummy
+        cell.store_direct(dummy, Release);
+        &(*dummy).next
+    }
+
+    // Returns the node with the key, or the first node after where it would be,
+    // and whether it has the key, along with the link on the pointer to it taken
+    // when it was read. Marked nodes on the way are unlinked
+    unsafe fn find<'g>(&'g self, start: &'g Bucket<K, V>, so_key: usize, key: Option<&K>,
+                       guard: &'g Guard) -> (LinkedPtr<'g, Node<K, V>>, *mut Node<K, V>, bool) {
+        'retry: loop {
+            let mut prev = start;
+            let mut ll = prev.load_linked(ordering::Acquire);
+            loop {
+                let (cur, marked) = ll.get_marked();
+                // The node holding prev was removed since
+                if marked {
+                    continue 'retry;
+                }
+                if cur.is_null() {
+                    return (ll, cur, false);
+                }
+                let next_ll = (*cur).next.load_linked(ordering::Acquire);
+                let (next, marked) = next_ll.get_marked();
+                if marked {
+                    match ll.store_conditional(next, ordering::Release) {
+                        Ok(()) => {
+                            retire(cur, guard);
+                            ll = prev.load_linked(ordering::Acquire);
+                        },
+                        Err(fail) => ll = fail.into_link(),
+                    }
+                    continue;
+                }
+                if (*cur).so_key > so_key {
+                    return (ll, cur, false);
+                }
+                // Entries with equal hashes are in no particular order
+                if (*cur).so_key == so_key {
+                    let same = match ((*cur).entry.as_ref(), key) {
+                        (Some(e), Some(key)) => e.0 == *key,
+                        (None, None) => true,
+                        _ => false,
+                    };
+                    if same {
+                        return (ll, cur, true);
+                    }
+                }
+                prev = &(*cur).next;
+                ll = next_ll;
+            }
+        }
+    }
+}
+
+impl<K: Hash + Eq, V> Default for HashMap<K, V, RandomState> {
+    fn default() -> HashMap<K, V, RandomState> {
+        HashMap::new()
+    }
+}
+
+impl<K, V, S> Drop for HashMap<K, V, S> {
+    fn drop(&mut self) {
+        // Every node still linked hangs off the dummy of bucket 0,
+        // the ones that were unlinked belong to the epoch
+        let head = self.segments[0].load(Relaxed);
+        let mut cur = unsafe { (*head).load(Relaxed) };
+        while !cur.is_null() {
+            let node = unsafe { Box::from_raw(cur) };
+            cur = node.next.load_marked(Relaxed).0;
+        }
+        for (segment, cell) in self.segments.iter().enumerate() {
+            let table = cell.load(Relaxed);
+            if !table.is_null() {
+                let len = segment_len(segment);
+                unsafe { drop(Box::from_raw(ptr::slice_from_raw_parts_mut(table, len))) };
+            }
+        }
+    }
+}
+
+unsafe impl<K: Send + Sync, V: Send + Sync, S: Send> Send for HashMap<K, V, S> {}
+unsafe impl<K: Send + Sync, V: Send + Sync, S: Sync> Sync for HashMap<K, V, S> {}
+
+#[cfg(test)]
+mod test {
+    use crossbeam::scope;
+    use epoch;
+    use std::hash::{BuildHasherDefault, Hasher};
+    use std::sync::atomic::Ordering::SeqCst;
+    use super::*;
+    use test_util::{Counted, flush_until};
+
+    // Sends every key to the same hash
+    #[derive(Default)]
+    struct Collide;
+
+    impl Hasher for Collide {
+        fn finish(&self) -> u64 {
+            7
+        }
+
+        fn write(&mut self, _: &[u8]) {}
+    }
+
+    #[test]
+    fn test_insert_remove() {
+        let map = HashMap::new();
+        assert!(map.is_empty());
+        for i in 0..10 {
+            assert!(map.insert(i, i * 10));
+        }
+        assert!(!map.insert(3, 0));
+        assert_eq!(map.len(), 10);
+        assert!(map.contains_key(&9));
+        assert!(!map.contains_key(&10));
+        {
+            let guard = epoch::pin();
+            assert_eq!(map.get(&3, &guard), Some(&30));
+        }
+        assert!(map.remove(&3));
+        assert!(!map.remove(&3));
+        assert!(!map.contains_key(&3));
+        assert_eq!(map.len(), 9);
+    }
+
+    #[test]
+    fn test_grow() {
+        let map = HashMap::new();
+        for i in 0..10000 {
+            assert!(map.insert(i, vec![i]));
+        }
+        assert!(map.buckets.load(Relaxed) >= 10000 / LOAD_FACTOR);
+        let guard = epoch::pin();
+        for i in 0..10000 {
+            assert_eq!(map.get(&i, &guard), Some(&vec![i]));
+        }
+        assert_eq!(map.get(&10000, &guard), None);
+    }
+
+    #[test]
+    fn test_hasher() {
+        let map = HashMap::with_hasher(BuildHasherDefault::<Collide>::default());
+        for i in 0..100 {
+            assert!(map.insert(i, i));
+        }
+        assert!(!map.insert(50, 0));
+        for i in (0..100).filter(|i| i % 2 == 0) {
+            assert!(map.remove(&i));
+        }
+        let guard = epoch::pin();
+        for i in 0..100 {
+            let expected = if i % 2 == 0 { None } else { Some(&i) };
+            assert_eq!(map.get(&i, &guard), expected);
+        }
+    }
+
+    // Threads fight over the same keys while the table keeps growing
+    // under them, so buckets are split while their entries churn
+    #[test]
+    fn test_mt_churn() {
+        let num_run: usize = 10000;
+        let num_threads = 4;
+        let map = HashMap::new();
+
+        let counts: Vec<(isize, isize)> = scope(|scope| {
+            let handles: Vec<_> = (0..num_threads).map(|t| {
+                let map = &map;
+                scope.spawn(move || {
+                    let (mut ins, mut rem) = (0, 0);
+                    for i in 0..num_run {
+                        let key = (i * 7 + t) % 512;
+                        if map.insert(key, vec![key; 4]) {
+                            ins += 1;
+                        }
+                        let guard = epoch::pin();
+                        if let Some(v) = map.get(&key, &guard) {
+                            assert!(v.iter().all(|x| *x == key));
+                        }
+                        if map.remove(&((i * 3 + t) % 512)) {
+                            rem += 1;
+                        }
+                    }
+                    (ins, rem)
+                })
+            }).collect();
+            handles.into_iter().map(|h| h.join()).collect()
+        });
+
+        let net: isize = counts.iter().map(|&(i, r)| i - r).sum();
+        let left = (0..512).filter(|k| map.contains_key(k)).count() as isize;
+        assert_eq!(left, net);
+        assert_eq!(map.len() as isize, net);
+    }
+
+    #[test]
+    fn test_drops() {
+        let drops = AtomicUsize::new(0);
+        let map = HashMap::new();
+        for i in 0..100 {
+            assert!(map.insert(i, Counted((), &drops)));
+        }
+        assert!(!map.insert(0, Counted((), &drops)));
+        assert_eq!(drops.load(SeqCst), 1);
+        for i in 0..50 {
+            assert!(map.remove(&i));
+        }
+        flush_until(|| drops.load(SeqCst), 51);
+        drop(map);
+        assert_eq!(drops.load(SeqCst), 101);
+    }
+}
@@ -0,0 +1,488 @@
+//! An open addressing hash set of integers
+//!
+//! Each slot is an ExclusiveUsize holding a key and its state, probed
+//! linearly. A key keeps the first slot it was inserted into: remove leaves
+//! it there as a tombstone and a later insert of the same key revives it, so
+//! a prober stops at the first slot with its key and never has to look past
+//! it for a second copy. Slots are only written with store_conditionals, which
+//! fail if the slot was written at all since it was linked, even if the same
+//! key was removed and inserted again in between.
+//!
+//! Tombstones of other keys are only cleared when the table is migrated, once
+//! too many slots are used. One thread freezes every slot, which makes every
+//! store_conditional on it fail, then copies the live keys into a new table,
+//! twice as large for a growable set or the same size for a fixed one.
+//! Threads which run into a frozen slot wait for the new table and retry there.
+
+use std::cmp;
+use std::thread;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::atomic::Ordering::{Relaxed, Acquire, Release};
+
+use ordering;
+use epoch::{self, Guard};
+use {ExclusivePtr, ExclusiveUsize};
+
+// The low bits of a slot are its state, and the rest is the key
+const LIVE: usize = 1;
+const DEAD: usize = 2;
+const FROZEN: usize = 4;
+const SHIFT: usize = 3;
+
+/// The largest key a set can hold
+pub const MAX_KEY: usize = !0 >> SHIFT;
+
+const MIN_SLOTS: usize = 16;
+
+struct Table {
+    slots: Box<[ExclusiveUsize]>,
+    // Slots holding a key, live or dead
+    used: AtomicUsize,
+}
+
+pub struct IntSet {
+    table: ExclusivePtr<Table>,
+    len: AtomicUsize,
+    // The most keys a fixed set holds
+    max_len: usize,
+    migrating: AtomicBool,
+    ops: AtomicUsize,
+    probes: AtomicUsize,
+    max_probes: ExclusiveUsize,
+}
+
+/// Counts of the slots looked at by insert, remove and contains
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProbeStats {
+    pub ops: usize,
+    pub probes: usize,
+    /// The most slots a single operation looked at
+    pub max: usize,
+}
+
+impl ProbeStats {
+
+    /// The mean number of slots looked at per operation
+    pub fn mean(&self) -> f64 {
+        match self.ops {
+            0 => 0.0,
+            ops => self.probes as f64 / ops as f64,
+        }
+    }
+}
+
+enum Probe<T> {
+    Done(T, usize),
+    // The table is being migrated, or has to be
+    Migrate(usize),
+}
+
+impl Table {
+
+    fn new(num_slots: usize) -> *mut Table {
+        Box::into_raw(Box::new(Table {
+            slots: (0..num_slots).map(|_| ExclusiveUsize::new(0)).collect::<Vec<_>>()
+                                 .into_boxed_slice(),
+            used: AtomicUsize::new(0),
+        }))
+    }
+
+    fn home(&self, key: usize) -> usize {
+        let hash = (key as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32;
+        hash as usize & (self.slots.len() - 1)
+    }
+
+    fn is_full(&self, used: usize) -> bool {
+        used * 4 > self.slots.len() * 3
+    }
+}
+
+// Enough slots that capacity keys don't fill the table
+fn slots_for(capacity: usize) -> usize {
+    cmp::max(capacity + capacity / 3 + 1, MIN_SLOTS).next_power_of_two()
+}
+
+impl IntSet {
+
+    /// Creates an empty growable set
+    pub fn new() -> IntSet {
+        IntSet::with_capacity(0)
+    }
+
+    /// Creates a growable set with room for capacity keys before it grows
+    pub fn with_capacity(capacity: usize) -> IntSet {
+        IntSet::create(slots_for(capacity), !0)
+    }
+
+    /// Creates a set which holds up to capacity keys and never grows
+    pub fn fixed(capacity: usize) -> IntSet {
+        IntSet::create(slots_for(capacity), capacity)
+    }
+
+    fn create(num_slots: usize, max_len: usize) -> IntSet {
+        IntSet {
+            table: ExclusivePtr::new(Table::new(num_slots)),
+            len: AtomicUsize::new(0),
+            max_len,
+            migrating: AtomicBool::new(false),
+            ops: AtomicUsize::new(0),
+            probes: AtomicUsize::new(0),
+            max_probes: ExclusiveUsize::new(0),
+        }
+    }
+
+    /// Inserts the key, returning whether it wasn't in the set yet
+    ///
+    /// Panics if the key is larger than MAX_KEY, or if the set is fixed and full
+    pub fn insert(&self, key: usize) -> bool {
+        match self.try_insert(key) {
+            Ok(inserted) => inserted,
+            Err(_) => panic!("the set is full"),
+        }
+    }
+
+    /// Inserts the key like insert, but hands it back if the set is fixed and full
+    ///
+    /// Panics if the key is larger than MAX_KEY
+    pub fn try_insert(&self, key: usize) -> Result<bool, usize> {
+        assert!(key <= MAX_KEY, "the key is larger than MAX_KEY");
+        // Counted before it's inserted, so a remove can't take the count below zero
+        if self.len.fetch_add(1, Relaxed) >= self.max_len {
+            self.len.fetch_sub(1, Relaxed);
+            if !self.contains(key) {
+                return Err(key);
+            }
+            return Ok(false);
+        }
+        let inserted = self.run(|table| unsafe { self.insert_in(&*table, key) });
+        if !inserted {
+            self.len.fetch_sub(1, Relaxed);
+        }
+        Ok(inserted)
+    }
+
+    /// Removes the key, returning whether it was in the set
+    pub fn remove(&self, key: usize) -> bool {
+        if key > MAX_KEY {
+            return false;
+        }
+        let removed = self.run(|table| unsafe { self.remove_in(&*table, key) });
+        if removed {
+            self.len.fetch_sub(1, Relaxed);
+        }
+        removed
+    }
+
+    pub fn contains(&self, key: usize) -> bool {
+        if key > MAX_KEY {
+            return false;
+        }
+        self.run(|table| unsafe { self.contains_in(&*table, key) })
+    }
+
+    /// Returns the number of keys, which may be stale by the time it returns
+    pub fn len(&self) -> usize {
+        self.len.load(Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of slots in the current table
+    pub fn num_slots(&self) -> usize {
+        let _guard = epoch::pin();
+        let table = unsafe { &*self.table.load(Acquire) };
+        table.slots.len()
+    }
+
+    pub fn probe_stats(&self) -> ProbeStats {
+        ProbeStats {
+            ops: self.ops.load(Relaxed),
+            probes: self.probes.load(Relaxed),
+            max: self.max_probes.load(Relaxed),
+        }
+    }
+
+    // Runs the probe on the current table until it finishes without a migration
+    fn run<T, F: Fn(*mut Table) -> Probe<T>>(&self, probe: F) -> T {
+        let guard = epoch::pin();
+        let mut probes = 0;
+        loop {
+            let table = self.table.load(Acquire);
+            match probe(table) {
+                Probe::Done(res, n) => {
+                    self.record(probes + n);
+                    return res;
+                },
+                Probe::Migrate(n) => {
+                    probes += n;
+                    self.migrate(table, &guard);
+                },
+            }
+        }
+    }
+
+    fn record(&self, probes: usize) {
+        self.ops.fetch_add(1, Relaxed);
+        self.probes.fetch_add(probes, Relaxed);
+        let mut ll = self.max_probes.load_linked(ordering::Relaxed);
+        while probes > ll.get() {
+            match ll.store_conditional(probes, ordering::Relaxed) {
+                Ok(()) => return,
+                Err(fail) => ll = fail.into_link(),
+            }
+        }
+    }
+
+    fn insert_in(&self, table: &Table, key: usize) -> Probe<bool> {
+        let len = table.slots.len();
+        let home = table.home(key);
+        let mut i = 0;
+        while i < len {
+            let slot = &table.slots[(home + i) & (len - 1)];
+            let ll = slot.load_linked(ordering::Acquire);
+            let val = ll.get();
+            if val & FROZEN != 0 {
+                return Probe::Migrate(i + 1);
+            }
+            if val == 0 {
+                if table.is_full(table.used.fetch_add(1, Relaxed) + 1) {
+                    table.used.fetch_sub(1, Relaxed);
+                    return Probe::Migrate(i + 1);
+                }
+                if ll.try_store_conditional(key << SHIFT | LIVE, ordering::Release) {
+                    return Probe::Done(true, i + 1);
+                }
+                // Look at the same slot again, someone may have put the key there
+                table.used.fetch_sub(1, Relaxed);
+                continue;
+            }
+            if val >> SHIFT == key {
+                if val & LIVE != 0 {
+                    return Probe::Done(false, i + 1);
+                }
+                if ll.try_store_conditional(key << SHIFT | LIVE, ordering::Release) {
+                    return Probe::Done(true, i + 1);
+                }
+                continue;
+            }
+            i += 1;
+        }
+        Probe::Migrate(len)
+    }
+
+    fn remove_in(&self, table: &Table, key: usize) -> Probe<bool> {
+        let len = table.slots.len();
+        let home = table.home(key);
+        let mut i = 0;
+        while i < len {
+            let slot = &table.slots[(home + i) & (len - 1)];
+            let ll = slot.load_linked(ordering::Acquire);
+            let val = ll.get();
+            if val & FROZEN != 0 {
+                return Probe::Migrate(i + 1);
+            }
+            if val == 0 {
+                return Probe::Done(false, i + 1);
+            }
+            if val >> SHIFT == key {
+                if val & DEAD != 0 {
+                    return Probe::Done(false, i + 1);
+                }
+                if ll.try_store_conditional(key << SHIFT | DEAD, ordering::Release) {
+                    return Probe::Done(true, i + 1);
+                }
+                continue;
+            }
+            i += 1;
+        }
+        Probe::Done(false, len)
+    }
+
+    fn contains_in(&self, table: &Table, key: usize) -> Probe<bool> {
+        let len = table.slots.len();
+        let home = table.home(key);
+        for i in 0..len {
+            let val = table.slots[(home + i) & (len - 1)].load(Acquire);
+            if val & FROZEN != 0 {
+                return Probe::Migrate(i + 1);
+            }
+            if val == 0 {
+                return Probe::Done(false, i + 1);
+            }
+            if val >> SHIFT == key {
+                return Probe::Done(val & LIVE != 0, i + 1);
+            }
+        }
+        Probe::Done(false, len)
+    }
+
+    // Replaces the table with one holding only its live keys,
+    // or waits for the thread which already is
+    fn migrate(&self, table: *mut Table, guard: &Guard) {
+        if self.migrating.swap(true, Acquire) {
+            while self.table.load(Acquire) == table {
+                thread::yield_now();
+            }
+            return;
+        }
+        if self.table.load(Acquire) != table {
+            self.migrating.store(false, Release);
+            return;
+        }
+        let old = unsafe { &*table };
+        let mut live = Vec::new();
+        for slot in old.slots.iter() {
+            let mut ll = slot.load_linked(ordering::Acquire);
+            loop {
+                let val = ll.get();
+                match ll.store_conditional(val | FROZEN, ordering::Relaxed) {
+                    Ok(()) => {
+                        if val & LIVE != 0 {
+                            live.push(val);
+                        }
+                        break;
+                    },
+                    Err(fail) => ll = fail.into_link(),
+                }
+            }
+        }
+        // A growable set only grows if a good share of the slots are live,
+        // otherwise clearing the tombstones makes enough room
+        let mut num_slots = old.slots.len();
+        if self.max_len == !0 && live.len() * 4 > num_slots {
+            num_slots *= 2;
+        }
+        let new = Table::new(num_slots);
+        unsafe {
+            // Nobody else can see the new table yet
+            for &val in &live {
+                let mut i = (*new).home(val >> SHIFT);
+                while (*new).slots[i].load(Relaxed) != 0 {
+                    i = (i + 1) & (num_slots - 1);
+                }
+                (*new).slots[i].store_direct(val, Relaxed);
+            }
+            (*new).used.store(live.len(), Relaxed);
+            self.table.store_direct(new, Release);
+            // Dropping the old table frees its slots along with it
+            guard.retire(table);
+        }
+        self.migrating.store(false, Release);
+    }
+}
+
+impl Default for IntSet {
+    fn default() -> IntSet {
+        IntSet::new()
+    }
+}
+
+impl Drop for IntSet {
+    fn drop(&mut self) {
+        unsafe { drop(Box::from_raw(self.table.load(Relaxed))) };
+    }
+}
+
+unsafe impl Send for IntSet {}
+unsafe impl Sync for IntSet {}
+
+#[cfg(test)]
+mod test {
+    use crossbeam::scope;
+    use super::*;
+
+    #[test]
+    fn test_insert_remove() {
+        let set = IntSet::new();
+        assert!(set.is_empty());
+        for &i in &[0, 5, 1, 3, MAX_KEY] {
+            assert!(set.insert(i));
+        }
+        assert!(!set.insert(3));
+        assert!(set.contains(0));
+        assert!(set.contains(MAX_KEY));
+        assert!(!set.contains(4));
+        assert!(!set.contains(MAX_KEY + 1));
+        assert_eq!(set.len(), 5);
+        assert!(set.remove(3));
+        assert!(!set.remove(3));
+        assert!(!set.contains(3));
+        // Revives the tombstone
+        assert!(set.insert(3));
+        assert!(set.contains(3));
+        assert_eq!(set.len(), 5);
+    }
+
+    #[test]
+    fn test_grow() {
+        let set = IntSet::new();
+        let start = set.num_slots();
+        for i in 0..10000 {
+            assert!(set.insert(i * 3));
+        }
+        assert!(set.num_slots() > start);
+        for i in 0..30000 {
+            assert_eq!(set.contains(i), i % 3 == 0);
+        }
+        let stats = set.probe_stats();
+        assert_eq!(stats.ops, 40000);
+        assert!(stats.max >= 1 && stats.mean() >= 1.0);
+    }
+
+    #[test]
+    fn test_fixed() {
+        let set = IntSet::fixed(4);
+        let num_slots = set.num_slots();
+        for i in 0..4 {
+            assert_eq!(set.try_insert(i), Ok(true));
+        }
+        assert_eq!(set.try_insert(4), Err(4));
+        assert_eq!(set.try_insert(2), Ok(false));
+        // Tombstones of ever new keys have to be cleared to make room
+        for i in 4..1000 {
+            assert!(set.remove(i - 4));
+            assert_eq!(set.try_insert(i), Ok(true));
+        }
+        assert_eq!(set.num_slots(), num_slots);
+        assert_eq!(set.len(), 4);
+        assert!((996..1000).all(|i| set.contains(i)));
+    }
+
+    // Threads fight over the same keys, so slots are constantly revived and
+    // removed while the table is migrated under them
+    #[test]
+    fn test_mt_churn() {
+        let num_run: usize = 20000;
+        let num_threads = 4;
+        let set = IntSet::fixed(64);
+
+        let counts: Vec<(isize, isize)> = scope(|scope| {
+            let handles: Vec<_> = (0..num_threads).map(|t| {
+                let set = &set;
+                scope.spawn(move || {
+                    let (mut ins, mut rem) = (0, 0);
+                    for i in 0..num_run {
+                        // Spread over more keys than fit, so the table keeps compacting
+                        let key = (i * 7 + t) % 16 + (i / 1000) * 16;
+                        if set.try_insert(key) == Ok(true) {
+                            ins += 1;
+                        }
+                        set.contains(key);
+                        if set.remove((i * 3 + t) % 16 + (i / 1000) * 16) {
+                            rem += 1;
+                        }
+                    }
+                    (ins, rem)
+                })
+            }).collect();
+            handles.into_iter().map(|h| h.join()).collect()
+        });
+
+        let net: isize = counts.iter().map(|&(i, r)| i - r).sum();
+        let left = (0..num_run).filter(|&k| set.contains(k)).count() as isize;
+        assert_eq!(left, net);
+        assert_eq!(set.len() as isize, net);
+    }
+}
@@ -0,0 +1,316 @@
+//! A Harris-Michael sorted linked list
+//!
+//! A node is removed in two steps. First its next pointer is marked, which
+//! deletes it logically and stops anything from being linked after it, then it
+//! is unlinked from its predecessor, by the remover or by whoever runs into it
+//! first. The mark stores back the next pointer it just linked, so it fails if
+//! the pointer was written in between. Traversals link every next pointer as
+//! they read it, and inserts and unlinks store_conditional through the link
+//! taken on the predecessor when the node was read. So they fail if the
+//! predecessor was written at all since, even if it points at the node again.
+//!
+//! Unlinked nodes are retired to the epoch, so a node can't be dropped
+//! and its memory reused while another thread is still traversing it.
+
+use std::marker::PhantomData;
+use std::sync::atomic::Ordering::{Relaxed, Acquire};
+
+use ordering;
+use epoch::{self, Guard};
+use {ExclusivePtr, LinkedPtr};
+
+struct Node<K, V> {
+    key: K,
+    val: V,
+    next: ExclusivePtr<Node<K, V>>,
+}
+
+pub struct List<K, V> {
+    head: ExclusivePtr<Node<K, V>>,
+    marker: PhantomData<Box<Node<K, V>>>,
+}
+
+/// Iterates over the entries in key order, valid for the life of the guard
+pub struct Iter<'g, K: 'g, V: 'g> {
+    cur: *mut Node<K, V>,
+    marker: PhantomData<&'g Node<K, V>>,
+}
+
+// Only the thread which unlinked a node may retire it
+unsafe fn retire<K, V>(node: *mut Node<K, V>, guard: &Guard) {
+    guard.retire(node);
+}
+
+impl<K: Ord, V> List<K, V> {
+
+    pub const fn new() -> List<K, V> {
+        List {
+            head: ExclusivePtr::NULL,
+            marker: PhantomData,
+        }
+    }
+
+    /// Inserts the entry if the key isn't in the list yet, returning whether it was
+    pub fn insert(&self, key: K, val: V) -> bool {
+        let guard = epoch::pin();
+        let node = Box::into_raw(Box::new(Node {
+            key,
+            val,
+            next: ExclusivePtr::NULL,
+        }));
+        loop {
+            let (prev, cur, found) = unsafe { self.find(&(*node).key, &guard) };
+            if found {
+                unsafe { drop(Box::from_raw(node)) };
+                return false;
+            }
+            // Nobody else can see the node yet
+            unsafe { (*node).next.store_direct(cur, Relaxed) };
+            if prev.try_store_conditional(node, ordering::Release) {
+                return true;
+            }
+        }
+    }
+
+    /// Removes the key, returning whether it was in the list
+    pub fn remove(&self, key: &K) -> bool {
+        let guard = epoch::pin();
+        loop {
+            let (prev, cur, found) = unsafe { self.find(key, &guard) };
+            if !found {
+                return false;
+            }
+            let ll = unsafe { (*cur).next.load_linked(ordering::Acquire) };
+            let (next, marked) = ll.get_marked();
+            // Someone else is removing it, so find again to help and see who won
+            if marked || !ll.try_store_conditional_marked(next, true, ordering::Relaxed) {
+                continue;
+            }
+            if prev.try_store_conditional(next, ordering::Release) {
+                unsafe { retire(cur, &guard) };
+            } else {
+                // Let a traversal unlink it instead
+                unsafe { self.find(key, &guard) };
+            }
+            return true;
+        }
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        let guard = epoch::pin();
+        unsafe { self.find(key, &guard).2 }
+    }
+
+    /// Returns the value for the key, valid for the life of the guard
+    pub fn get<'g>(&'g self, key: &K, guard: &'g Guard) -> Option<&'g V> {
+        unsafe {
+            match self.find(key, guard) {
+                (_, cur, true) => Some(&(*cur).val),
+                _ => None,
+            }
+        }
+    }
+
+    pub fn iter<'g>(&'g self, _: &'g Guard) -> Iter<'g, K, V> {
+        Iter {
+            cur: self.head.load(Acquire),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let guard = epoch::pin();
+        self.iter(&guard).next().is_none()
+    }
+
+    // Returns the first node whose key is not less than key and whether its key
+    // is equal, along with the link on the pointer to it taken when it was read.
+    // Marked nodes on the way are unlinked
+    unsafe fn find<'g>(&'g self, key: &K, guard: &'g Guard)
+                       -> (LinkedPtr<'g, Node<K, V>>, *mut Node<K, V>, bool) {
+        'retry: loop {
+            let mut prev = &self.head;
+            let mut ll = prev.load_linked(ordering::Acquire);
+            loop {
+                let (cur, marked) = ll.get_marked();
+                // The node holding prev was removed since
+                if marked {
+                    continue 'retry;
+                }
+                if cur.is_null() {
+                    return (ll, cur, false);
+                }
+                let next_ll = (*cur).next.load_linked(ordering::Acquire);
+                let (next, marked) = next_ll.get_marked();
+                if marked {
+                    match ll.store_conditional(next, ordering::Release) {
+                        Ok(()) => {
+                            retire(cur, guard);
+                            ll = prev.load_linked(ordering::Acquire);
+                        },
+                        Err(fail) => ll = fail.into_link(),
+                    }
+                } else if (*cur).key >= *key {
+                    return (ll, cur, (*cur).key == *key);
+                } else {
+                    prev = &(*cur).next;
+                    ll = next_ll;
+                }
+            }
+        }
+    }
+}
+
+impl<K: Ord, V> Default for List<K, V> {
+    fn default() -> List<K, V> {
+        List::new()
+    }
+}
+
+impl<K, V> Drop for List<K, V> {
+    fn drop(&mut self) {
+        // Removed nodes which are still linked are freed here,
+        // the ones that were unlinked belong to the epoch
+        let mut cur = self.head.load(Relaxed);
+        while !cur.is_null() {
+            let node = unsafe { Box::from_raw(cur) };
+            cur = node.next.load_marked(Relaxed).0;
+        }
+    }
+}
+
+unsafe impl<K: Send + Sync, V: Send + Sync> Send for List<K, V> {}
+unsafe impl<K: Send + Sync, V: Send + Sync> Sync for List<K, V> {}
+
+impl<'g, K, V> Iterator for Iter<'g, K, V> {
+    type Item = (&'g K, &'g V);
+
+    fn next(&mut self) -> Option<(&'g K, &'g V)> {
+        // Keys only increase along next pointers, even through removed nodes
+        while !self.cur.is_null() {
+            let node = unsafe { &*self.cur };
+            let (next, marked) = node.next.load_marked(Acquire);
+            self.cur = next;
+            if !marked {
+                return Some((&node.key, &node.val));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crossbeam::scope;
+    use epoch;
+    use super::*;
+    use test_util::{Counted, flush_until};
+    use std::ptr;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::SeqCst;
+
+    #[test]
+    fn test_insert_remove() {
+        let list = List::new();
+        assert!(list.is_empty());
+        for &i in &[5, 1, 3, 9, 7] {
+            assert!(list.insert(i, i * 10));
+        }
+        assert!(!list.insert(3, 0));
+        assert!(list.contains(&9));
+        assert!(!list.contains(&4));
+        {
+            let guard = epoch::pin();
+            assert_eq!(list.get(&3, &guard), Some(&30));
+            let keys: Vec<_> = list.iter(&guard).map(|(k, _)| *k).collect();
+            assert_eq!(keys, vec![1, 3, 5, 7, 9]);
+        }
+        assert!(list.remove(&3));
+        assert!(!list.remove(&3));
+        assert!(!list.contains(&3));
+        assert!(list.remove(&1));
+        assert!(list.remove(&9));
+        let guard = epoch::pin();
+        let keys: Vec<_> = list.iter(&guard).map(|(k, _)| *k).collect();
+        assert_eq!(keys, vec![5, 7]);
+    }
+
+    #[test]
+    fn test_stale_link() {
+        let list = List::new();
+        assert!(list.insert(1, ()));
+        assert!(list.insert(3, ()));
+        let guard = epoch::pin();
+        unsafe {
+            let (prev, cur, found) = list.find(&2, &guard);
+            assert!(!found);
+            // Stores the same pointer again, which a cas wouldn't notice
+            let (_, one, _) = list.find(&1, &guard);
+            let ll = (*one).next.load_linked(ordering::Relaxed);
+            ll.store_conditional(cur, ordering::Relaxed).unwrap();
+            assert!(!prev.try_store_conditional(ptr::null_mut(), ordering::Relaxed));
+        }
+        let keys: Vec<_> = list.iter(&guard).map(|(k, _)| *k).collect();
+        assert_eq!(keys, vec![1, 3]);
+    }
+
+    // Threads fight over the same few keys, so nodes are constantly
+    // marked, unlinked and replaced by new ones at the same place
+    #[test]
+    fn test_mt_churn() {
+        let num_run: usize = 10000;
+        let num_threads = 4;
+        let list = List::new();
+
+        let counts: Vec<(isize, isize)> = scope(|scope| {
+            let handles: Vec<_> = (0..num_threads).map(|t| {
+                let list = &list;
+                scope.spawn(move || {
+                    let (mut ins, mut rem) = (0, 0);
+                    for i in 0..num_run {
+                        let key = (i * 7 + t) % 16;
+                        if list.insert(key, vec![key; 4]) {
+                            ins += 1;
+                        }
+                        let guard = epoch::pin();
+                        if let Some(v) = list.get(&key, &guard) {
+                            assert!(v.iter().all(|x| *x == key));
+                        }
+                        let mut last = None;
+                        for (k, _) in list.iter(&guard) {
+                            assert!(last < Some(*k));
+                            last = Some(*k);
+                        }
+                        if list.remove(&((i * 3 + t) % 16)) {
+                            rem += 1;
+                        }
+                    }
+                    (ins, rem)
+                })
+            }).collect();
+            handles.into_iter().map(|h| h.join()).collect()
+        });
+
+        let guard = epoch::pin();
+        let left = list.iter(&guard).count() as isize;
+        let net: isize = counts.iter().map(|&(i, r)| i - r).sum();
+        assert_eq!(left, net);
+    }
+
+    #[test]
+    fn test_drops() {
+        let drops = AtomicUsize::new(0);
+        let list = List::new();
+        for i in 0..100 {
+            assert!(list.insert(i, Counted((), &drops)));
+        }
+        assert!(!list.insert(0, Counted((), &drops)));
+        assert_eq!(drops.load(SeqCst), 1);
+        for i in 0..50 {
+            assert!(list.remove(&i));
+        }
+        flush_until(|| drops.load(SeqCst), 51);
+        drop(list);
+        assert_eq!(drops.load(SeqCst), 101);
+    }
+}
@@ -0,0 +1,21 @@
+//! Lock-free collections built on ExclusivePtr
+
+mod stack;
+mod queue;
+mod array_queue;
+mod list;
+mod skip_map;
+mod hash_map;
+mod int_set;
+mod deque;
+mod elimination;
+
+pub use self::stack::{Stack, TakeAll};
+pub use self::queue::{Queue, Drain};
+pub use self::array_queue::ArrayQueue;
+pub use self::list::{List, Iter};
+pub use self::skip_map::{SkipMap, Range};
+pub use self::hash_map::HashMap;
+pub use self::int_set::{IntSet, ProbeStats, MAX_KEY};
+pub use self::deque::{Deque, Stealer, Stolen};
+pub use self::elimination::{EliminationStack, EliminationConfig};
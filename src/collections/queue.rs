@@ -0,0 +1,340 @@
+//! A Michael-Scott queue
+//!
+//! The head, the tail, and the next pointer of every node are ExclusivePtrs
+//! updated through links. Like in the original paper, nodes are recycled
+//! through a free list instead of freed, and a stale thread working on a
+//! node which has been dequeued and enqueued again fails its store_conditional
+//! where a plain cas would succeed and corrupt the queue.
+//!
+//! The head points at a dummy node, and a pop takes its value out of the node
+//! after the dummy, which then becomes the new dummy. So a node is only put
+//! back on the free list once its value has been taken and the head has moved
+//! past it, whichever happens last.
+//!
+//! A pop which finds the queue empty doesn't write anything. It reads the
+//! head again instead, and the queue was empty if the head held the same
+//! node, with the same counter and the same node generation, throughout.
+
+use std::ptr;
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, AtomicPtr};
+use std::sync::atomic::Ordering::{Relaxed, Acquire, AcqRel};
+
+use ordering;
+use ExclusivePtr;
+
+struct Node<T> {
+    val: UnsafeCell<Option<T>>,
+    next: ExclusivePtr<Node<T>>,
+    // Atomic since a stale popper of the free list may read it
+    // while the node is being pushed again
+    free_next: AtomicPtr<Node<T>>,
+    released: AtomicUsize,
+    // Bumped each time the node comes off the free list, since the head's
+    // counter doesn't catch a recycled node on powerpc, which has none
+    generation: AtomicUsize,
+}
+
+pub struct Queue<T> {
+    head: ExclusivePtr<Node<T>>,
+    tail: ExclusivePtr<Node<T>>,
+    free: ExclusivePtr<Node<T>>,
+    marker: PhantomData<T>,
+}
+
+/// Pops values until the queue is empty
+pub struct Drain<'a, T: 'a> {
+    queue: &'a Queue<T>,
+}
+
+impl<T> Queue<T> {
+
+    pub fn new() -> Queue<T> {
+        let dummy = Box::into_raw(Box::new(Node {
+            val: UnsafeCell::new(None),
+            next: ExclusivePtr::NULL,
+            free_next: AtomicPtr::new(ptr::null_mut()),
+            // The dummy has no value to take
+            released: AtomicUsize::new(1),
+            generation: AtomicUsize::new(0),
+        }));
+        Queue {
+            head: ExclusivePtr::new(dummy),
+            tail: ExclusivePtr::new(dummy),
+            free: ExclusivePtr::NULL,
+            marker: PhantomData,
+        }
+    }
+
+    pub fn push(&self, val: T) {
+        let node = self.alloc(val);
+        loop {
+            let tail_ll = self.tail.load_linked(ordering::Acquire);
+            let tail = tail_ll.get();
+            let next_ll = unsafe { (*tail).next.load_linked(ordering::Acquire) };
+            if !next_ll.get().is_null() {
+                self.help_tail();
+                continue;
+            }
+            // A tail which has since been recycled had its next stored to,
+            // so checking that it's the tail again is enough
+            if self.tail.load(Acquire) != tail {
+                continue;
+            }
+            if next_ll.store_conditional(node, ordering::Release).is_ok() {
+                // Failing is fine, someone else helped already
+                let _ = tail_ll.try_store_conditional(node, ordering::Release);
+                return;
+            }
+        }
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        loop {
+            let head_ll = self.head.load_linked(ordering::Acquire);
+            let head = head_ll.get();
+            let next = unsafe { (*head).next.load(Acquire) };
+            if next.is_null() {
+                match self.still_head(head) {
+                    true => return None,
+                    false => continue,
+                }
+            }
+            // The head can't pass the tail, or the tail would point at a recycled node
+            if self.tail.load(Acquire) == head {
+                self.help_tail();
+                continue;
+            }
+            if head_ll.try_store_conditional(next, ordering::Release) {
+                unsafe {
+                    let val = (*(*next).val.get()).take();
+                    self.release(next);
+                    self.release(head);
+                    return val;
+                }
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let head = self.head.load(Acquire);
+        unsafe { (*head).next.load(Acquire).is_null() }
+    }
+
+    pub fn drain(&self) -> Drain<'_, T> {
+        Drain { queue: self }
+    }
+
+    // Whether the node stays the head while its next pointer is read as null.
+    // The head can only come back to a node after it went through the free
+    // list, and the generation is bumped before it is pushed again
+    fn still_head(&self, node: *mut Node<T>) -> bool {
+        let (head, counter) = self.head.load_counted(Acquire);
+        if head != node {
+            return false;
+        }
+        let generation = unsafe { (*node).generation.load(Acquire) };
+        // The generation only counts if it was read while the node was the head
+        if self.head.load(Acquire) != node {
+            return false;
+        }
+        let next = unsafe { (*node).next.load(Acquire) };
+        let (head, now) = self.head.load_counted(Acquire);
+        next.is_null() && head == node && now == counter &&
+            unsafe { (*node).generation.load(Acquire) } == generation
+    }
+
+    // Moves the tail forwards if it is lagging behind a push
+    fn help_tail(&self) {
+        let tail_ll = self.tail.load_linked(ordering::Acquire);
+        let next = unsafe { (*tail_ll.get()).next.load(Acquire) };
+        if !next.is_null() {
+            let _ = tail_ll.try_store_conditional(next, ordering::Release);
+        }
+    }
+
+    fn alloc(&self, val: T) -> *mut Node<T> {
+        let mut ll = self.free.load_linked(ordering::Acquire);
+        loop {
+            let node = ll.get();
+            if node.is_null() {
+                return Box::into_raw(Box::new(Node {
+                    val: UnsafeCell::new(Some(val)),
+                    next: ExclusivePtr::NULL,
+                    free_next: AtomicPtr::new(ptr::null_mut()),
+                    released: AtomicUsize::new(0),
+                    generation: AtomicUsize::new(0),
+                }));
+            }
+            let free_next = unsafe { (*node).free_next.load(Relaxed) };
+            match ll.store_conditional(free_next, ordering::Relaxed) {
+                Ok(()) => unsafe {
+                    (*node).generation.fetch_add(1, Relaxed);
+                    *(*node).val.get() = Some(val);
+                    (*node).released.store(0, Relaxed);
+                    return node;
+                },
+                Err(fail) => ll = fail.into_link(),
+            }
+        }
+    }
+
+    unsafe fn release(&self, node: *mut Node<T>) {
+        if (*node).released.fetch_add(1, AcqRel) == 0 {
+            return;
+        }
+        // Any link a stale push holds on next was taken before the node was
+        // linked onto, which bumped the version, so a direct store can't revive it
+        (*node).next.store_direct(ptr::null_mut(), Relaxed);
+        let mut ll = self.free.load_linked(ordering::Relaxed);
+        loop {
+            (*node).free_next.store(ll.get(), Relaxed);
+            match ll.store_conditional(node, ordering::Release) {
+                Ok(()) => return,
+                Err(fail) => ll = fail.into_link(),
+            }
+        }
+    }
+}
+
+impl<T> Default for Queue<T> {
+    fn default() -> Queue<T> {
+        Queue::new()
+    }
+}
+
+impl<T> Drop for Queue<T> {
+    fn drop(&mut self) {
+        unsafe {
+            let mut node = self.head.load(Relaxed);
+            while !node.is_null() {
+                let next = (*node).next.load(Relaxed);
+                drop(Box::from_raw(node));
+                node = next;
+            }
+            let mut node = self.free.load(Relaxed);
+            while !node.is_null() {
+                let next = (*node).free_next.load(Relaxed);
+                drop(Box::from_raw(node));
+                node = next;
+            }
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for Queue<T> {}
+unsafe impl<T: Send> Sync for Queue<T> {}
+
+impl<'a, T> Iterator for Drain<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.queue.pop()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate crossbeam;
+    use self::crossbeam::scope;
+    use super::*;
+    use std::sync::atomic::Ordering::Relaxed;
+
+    #[test]
+    fn test_push_pop() {
+        let queue = Queue::new();
+        assert!(queue.is_empty());
+        assert_eq!(queue.pop(), None);
+        for i in 0..10 {
+            queue.push(vec![i]);
+        }
+        assert!(!queue.is_empty());
+        for i in 0..5 {
+            assert_eq!(queue.pop(), Some(vec![i]));
+        }
+        // reuses the popped nodes
+        for i in 10..15 {
+            queue.push(vec![i]);
+        }
+        assert_eq!(queue.drain().collect::<Vec<_>>(), (5..15).map(|i| vec![i]).collect::<Vec<_>>());
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_empty_pop_reads_only() {
+        let queue = Queue::new();
+        queue.push(1);
+        assert_eq!(queue.pop(), Some(1));
+        let before = queue.head.load_counted(Relaxed);
+        for _ in 0..10 {
+            assert_eq!(queue.pop(), None);
+        }
+        assert_eq!(queue.head.load_counted(Relaxed), before);
+    }
+
+    #[test]
+    fn test_spsc_order() {
+        let num_run: usize = 100000;
+        let queue = Queue::new();
+        scope(|scope| {
+            scope.spawn(|| {
+                for i in 0..num_run {
+                    queue.push(i);
+                }
+            });
+            let mut expected = 0;
+            while expected < num_run {
+                if let Some(i) = queue.pop() {
+                    assert_eq!(i, expected);
+                    expected += 1;
+                }
+            }
+        });
+        assert_eq!(queue.pop(), None);
+    }
+
+    // Values from a single producer must come out in the order they went in,
+    // and every value must come out exactly once
+    #[test]
+    fn test_mpmc() {
+        let num_run: usize = 20000;
+        let num_threads = 4;
+        let queue = Queue::new();
+
+        let popped: Vec<Vec<(usize, usize)>> = scope(|scope| {
+            for p in 0..num_threads {
+                let queue = &queue;
+                scope.spawn(move || {
+                    for i in 0..num_run {
+                        queue.push((p, i));
+                    }
+                });
+            }
+            let handles: Vec<_> = (0..num_threads).map(|_| {
+                let queue = &queue;
+                scope.spawn(move || {
+                    let mut last = vec![None; num_threads];
+                    let mut popped = Vec::new();
+                    while popped.len() < num_run {
+                        if let Some((p, i)) = queue.pop() {
+                            assert!(last[p] < Some(i));
+                            last[p] = Some(i);
+                            popped.push((p, i));
+                        }
+                    }
+                    popped
+                })
+            }).collect();
+            handles.into_iter().map(|h| h.join()).collect()
+        });
+
+        let mut all: Vec<_> = popped.into_iter().flatten().collect();
+        all.sort();
+        let expected: Vec<_> = (0..num_threads)
+            .flat_map(|p| (0..num_run).map(move |i| (p, i)))
+            .collect();
+        assert_eq!(all, expected);
+        assert!(queue.is_empty());
+    }
+}
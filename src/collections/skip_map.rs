@@ -0,0 +1,475 @@
+//! A lock-free skip list map
+//!
+//! Each level is a Harris-Michael list, with the mark bit of a node's next
+//! pointer at a level deleting it from that level. Removal marks the levels
+//! from the top down, and marking level 0 is the point where the key is gone.
+//! Links and unlinks take their link on the tower after the search and check
+//! it still holds the successor found, so like a cas they only fail if it
+//! changed value, while marking stores back the pointer it just linked.
+//!
+//! A node can still be linked at an upper level by its inserter after it was
+//! marked, so it is only retired to the epoch once both the inserter is done
+//! with its tower and the remover has unlinked it, each of them searching for
+//! the key afterwards to unlink it from any level it is left on.
+
+use std::cell::Cell;
+use std::cmp;
+use std::ops::{Bound, RangeBounds, RangeFull};
+use std::ptr;
+use std::marker::PhantomData;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::{Relaxed, Acquire, AcqRel};
+
+use ordering;
+use epoch::{self, Guard};
+use ExclusivePtr;
+
+const MAX_HEIGHT: usize = 32;
+
+static NEXT_SEED: AtomicUsize = AtomicUsize::new(1);
+
+thread_local!(static SEED: Cell<u32> =
+    Cell::new((NEXT_SEED.fetch_add(1, Relaxed) as u32).wrapping_mul(0x9e37_79b9) | 1));
+
+// Each level is half as likely as the one below it
+fn random_height() -> usize {
+    SEED.with(|seed| {
+        let mut x = seed.get();
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        seed.set(x);
+        cmp::min(x.trailing_zeros() as usize + 1, MAX_HEIGHT)
+    })
+}
+
+struct Node<K, V> {
+    key: K,
+    val: V,
+    // One reference for the inserter until the tower is built,
+    // and one for the list until the node is unlinked
+    refs: AtomicUsize,
+    next: Box<[ExclusivePtr<Node<K, V>>]>,
+}
+
+pub struct SkipMap<K, V> {
+    head: [ExclusivePtr<Node<K, V>>; MAX_HEIGHT],
+    marker: PhantomData<Box<Node<K, V>>>,
+}
+
+/// Iterates over the entries with keys in the range, in order
+pub struct Range<'g, K: 'g, V: 'g, R> {
+    cur: *mut Node<K, V>,
+    range: R,
+    marker: PhantomData<&'g Node<K, V>>,
+}
+
+// The links before and after the key at every level
+struct Position<'g, K: 'g, V: 'g> {
+    preds: [&'g ExclusivePtr<Node<K, V>>; MAX_HEIGHT],
+    succs: [*mut Node<K, V>; MAX_HEIGHT],
+    found: bool,
+}
+
+unsafe fn release<K, V>(node: *mut Node<K, V>, guard: &Guard) {
+    if (*node).refs.fetch_sub(1, AcqRel) == 1 {
+        // Dropping the node frees its tower along with it
+        guard.retire(node);
+    }
+}
+
+impl<K: Ord, V> SkipMap<K, V> {
+
+    pub const fn new() -> SkipMap<K, V> {
+        SkipMap {
+            head: [ExclusivePtr::NULL; MAX_HEIGHT],
+            marker: PhantomData,
+        }
+    }
+
+    /// Inserts the entry if the key isn't in the map yet, returning whether it was
+    pub fn insert(&self, key: K, val: V) -> bool {
+        let guard = epoch::pin();
+        let height = random_height();
+        let node = Box::into_raw(Box::new(Node {
+            key,
+            val,
+            refs: AtomicUsize::new(2),
+            next: (0..height).map(|_| ExclusivePtr::NULL).collect::<Vec<_>>().into_boxed_slice(),
+        }));
+        unsafe {
+            let key = &(*node).key;
+            let mut pos = loop {
+                let pos = self.find(key, &guard);
+                if pos.found {
+                    drop(Box::from_raw(node));
+                    return false;
+                }
+                // Nobody else can see the node yet
+                for level in 0..height {
+                    (*node).next[level].store_direct(pos.succs[level], Relaxed);
+                }
+                let ll = pos.preds[0].load_linked(ordering::Acquire);
+                if ll.get() == pos.succs[0] && ll.try_store_conditional(node, ordering::Release) {
+                    break pos;
+                }
+            };
+
+            'build: for level in 1..height {
+                loop {
+                    // Point the node at the current successor, unless it's being removed
+                    let ll = (*node).next[level].load_linked(ordering::Acquire);
+                    let (next, marked) = ll.get_marked();
+                    if marked {
+                        break 'build;
+                    }
+                    if next != pos.succs[level] &&
+                       !ll.try_store_conditional(pos.succs[level], ordering::Relaxed) {
+                        continue;
+                    }
+                    let ll = pos.preds[level].load_linked(ordering::Acquire);
+                    if ll.get() == pos.succs[level] &&
+                       ll.try_store_conditional(node, ordering::Release) {
+                        break;
+                    }
+                    pos = self.find(key, &guard);
+                    if pos.succs[0] != node {
+                        break 'build;
+                    }
+                }
+            }
+
+            // A removal which raced with the links above may have missed some
+            if (*node).next[0].load_marked(Acquire).1 {
+                self.find(key, &guard);
+            }
+            release(node, &guard);
+        }
+        true
+    }
+
+    /// Removes the key, returning whether it was in the map
+    pub fn remove(&self, key: &K) -> bool {
+        let guard = epoch::pin();
+        unsafe {
+            let pos = self.find(key, &guard);
+            if !pos.found {
+                return false;
+            }
+            let node = pos.succs[0];
+            let tower = &(*node).next;
+            for level in (1..tower.len()).rev() {
+                loop {
+                    let ll = tower[level].load_linked(ordering::Acquire);
+                    let (next, marked) = ll.get_marked();
+                    if marked || ll.try_store_conditional_marked(next, true, ordering::Relaxed) {
+                        break;
+                    }
+                }
+            }
+            loop {
+                let ll = tower[0].load_linked(ordering::Acquire);
+                let (next, marked) = ll.get_marked();
+                if marked {
+                    // Someone else removed it first
+                    return false;
+                }
+                if ll.try_store_conditional_marked(next, true, ordering::Relaxed) {
+                    break;
+                }
+            }
+            self.find(key, &guard);
+            release(node, &guard);
+        }
+        true
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        let guard = epoch::pin();
+        unsafe { self.find(key, &guard).found }
+    }
+
+    /// Returns the value for the key, valid for the life of the guard
+    pub fn get<'g>(&'g self, key: &K, guard: &'g Guard) -> Option<&'g V> {
+        unsafe {
+            let pos = self.find(key, guard);
+            match pos.found {
+                true => Some(&(*pos.succs[0]).val),
+                false => None,
+            }
+        }
+    }
+
+    /// Iterates over the entries with keys in the range
+    pub fn range<'g, R: RangeBounds<K>>(&'g self, range: R, _: &'g Guard) -> Range<'g, K, V, R> {
+        Range {
+            cur: unsafe { self.seek(range.start_bound()) },
+            range,
+            marker: PhantomData,
+        }
+    }
+
+    pub fn iter<'g>(&'g self, guard: &'g Guard) -> Range<'g, K, V, RangeFull> {
+        self.range(.., guard)
+    }
+
+    pub fn first<'g>(&'g self, guard: &'g Guard) -> Option<(&'g K, &'g V)> {
+        self.iter(guard).next()
+    }
+
+    pub fn last<'g>(&'g self, _: &'g Guard) -> Option<(&'g K, &'g V)> {
+        unsafe {
+            let mut tower = &self.head[..];
+            let mut last = ptr::null_mut();
+            for level in (0..MAX_HEIGHT).rev() {
+                let mut cur = tower[level].load_marked(Acquire).0;
+                // Removed nodes can be stepped over, but not ended on
+                while !cur.is_null() {
+                    if !(*cur).next[0].load_marked(Acquire).1 {
+                        last = cur;
+                        tower = &(*cur).next;
+                    }
+                    cur = (*cur).next[level].load_marked(Acquire).0;
+                }
+            }
+            last.as_ref().map(|node: &Node<K, V>| (&node.key, &node.val))
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let guard = epoch::pin();
+        self.first(&guard).is_none()
+    }
+
+    // Finds the links around the key at every level, unlinking the marked nodes on the way
+    unsafe fn find<'g>(&'g self, key: &K, _: &'g Guard) -> Position<'g, K, V> {
+        'retry: loop {
+            let mut pos = Position {
+                preds: [&self.head[0]; MAX_HEIGHT],
+                succs: [ptr::null_mut(); MAX_HEIGHT],
+                found: false,
+            };
+            let mut tower = &self.head[..];
+            for level in (0..MAX_HEIGHT).rev() {
+                let mut prev = &tower[level];
+                let (mut cur, marked) = prev.load_marked(Acquire);
+                if marked {
+                    continue 'retry;
+                }
+                loop {
+                    if cur.is_null() {
+                        break;
+                    }
+                    let (next, marked) = (*cur).next[level].load_marked(Acquire);
+                    // prev is marked too if its node was removed since
+                    if prev.load(Acquire) != cur {
+                        continue 'retry;
+                    }
+                    if marked {
+                        let ll = prev.load_linked(ordering::Acquire);
+                        if ll.get() != cur || !ll.try_store_conditional(next, ordering::Release) {
+                            continue 'retry;
+                        }
+                        cur = next;
+                        continue;
+                    }
+                    if (*cur).key >= *key {
+                        break;
+                    }
+                    tower = &(*cur).next;
+                    prev = &tower[level];
+                    cur = next;
+                }
+                pos.preds[level] = prev;
+                pos.succs[level] = cur;
+            }
+            let first = pos.succs[0];
+            pos.found = !first.is_null() && (*first).key == *key;
+            return pos;
+        }
+    }
+
+    // Returns the first node at level 0 which may be past the bound, without unlinking anything
+    unsafe fn seek(&self, bound: Bound<&K>) -> *mut Node<K, V> {
+        let before = |key: &K| match bound {
+            Bound::Included(b) => key < b,
+            Bound::Excluded(b) => key <= b,
+            Bound::Unbounded => false,
+        };
+        let mut tower = &self.head[..];
+        for level in (1..MAX_HEIGHT).rev() {
+            let mut cur = tower[level].load_marked(Acquire).0;
+            while !cur.is_null() && before(&(*cur).key) {
+                tower = &(*cur).next;
+                cur = tower[level].load_marked(Acquire).0;
+            }
+        }
+        let mut cur = tower[0].load_marked(Acquire).0;
+        while !cur.is_null() && before(&(*cur).key) {
+            cur = (*cur).next[0].load_marked(Acquire).0;
+        }
+        cur
+    }
+}
+
+impl<K: Ord, V> Default for SkipMap<K, V> {
+    fn default() -> SkipMap<K, V> {
+        SkipMap::new()
+    }
+}
+
+impl<K, V> Drop for SkipMap<K, V> {
+    fn drop(&mut self) {
+        // Every node still linked is linked at level 0,
+        // the ones that were unlinked belong to the epoch
+        let mut cur = self.head[0].load(Relaxed);
+        while !cur.is_null() {
+            let node = unsafe { Box::from_raw(cur) };
+            cur = node.next[0].load_marked(Relaxed).0;
+        }
+    }
+}
+
+unsafe impl<K: Send + Sync, V: Send + Sync> Send for SkipMap<K, V> {}
+unsafe impl<K: Send + Sync, V: Send + Sync> Sync for SkipMap<K, V> {}
+
+impl<'g, K: Ord, V, R: RangeBounds<K>> Iterator for Range<'g, K, V, R> {
+    type Item = (&'g K, &'g V);
+
+    fn next(&mut self) -> Option<(&'g K, &'g V)> {
+        while !self.cur.is_null() {
+            let node = unsafe { &*self.cur };
+            let (next, marked) = node.next[0].load_marked(Acquire);
+            let past = match self.range.end_bound() {
+                Bound::Included(end) => node.key > *end,
+                Bound::Excluded(end) => node.key >= *end,
+                Bound::Unbounded => false,
+            };
+            if past {
+                self.cur = ptr::null_mut();
+                return None;
+            }
+            self.cur = next;
+            if !marked {
+                return Some((&node.key, &node.val));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crossbeam::scope;
+    use epoch;
+    use super::*;
+    use test_util::{Counted, flush_until};
+    use std::sync::atomic::Ordering::SeqCst;
+
+    #[test]
+    fn test_insert_remove() {
+        let map = SkipMap::new();
+        assert!(map.is_empty());
+        for i in 0..100 {
+            assert!(map.insert((i * 37) % 100, i));
+        }
+        assert!(!map.insert(5, 0));
+        assert!(map.contains_key(&99));
+        for i in (0..100).filter(|i| i % 3 == 0) {
+            assert!(map.remove(&i));
+        }
+        assert!(!map.remove(&3));
+        assert!(!map.contains_key(&3));
+
+        let guard = epoch::pin();
+        assert_eq!(map.get(&37, &guard), Some(&1));
+        let keys: Vec<_> = map.iter(&guard).map(|(k, _)| *k).collect();
+        assert_eq!(keys, (0..100).filter(|i| i % 3 != 0).collect::<Vec<_>>());
+        assert_eq!(map.first(&guard), Some((&1, &73)));
+        assert_eq!(map.last(&guard).map(|(k, _)| *k), Some(98));
+    }
+
+    #[test]
+    fn test_range() {
+        let map = SkipMap::new();
+        for i in 0..50 {
+            map.insert(i * 2, ());
+        }
+        let guard = epoch::pin();
+        let keys = |r: Vec<_>| r.into_iter().map(|(k, _): (&i32, &())| *k).collect::<Vec<_>>();
+        assert_eq!(keys(map.range(10..16, &guard).collect()), vec![10, 12, 14]);
+        assert_eq!(keys(map.range(11..=16, &guard).collect()), vec![12, 14, 16]);
+        assert_eq!(keys(map.range((Bound::Excluded(94), Bound::Unbounded), &guard).collect()),
+                   vec![96, 98]);
+        assert!(map.range(200.., &guard).next().is_none());
+    }
+
+    // Threads fight over a small key space, so towers are often
+    // still being built when their nodes are removed
+    #[test]
+    fn test_mt_churn() {
+        let num_run: usize = 10000;
+        let num_threads = 4;
+        let map = SkipMap::new();
+
+        let counts: Vec<(isize, isize)> = scope(|scope| {
+            let handles: Vec<_> = (0..num_threads).map(|t| {
+                let map = &map;
+                scope.spawn(move || {
+                    let (mut ins, mut rem) = (0, 0);
+                    for i in 0..num_run {
+                        let key = (i * 7 + t) % 64;
+                        if map.insert(key, vec![key; 4]) {
+                            ins += 1;
+                        }
+                        let guard = epoch::pin();
+                        if let Some(v) = map.get(&key, &guard) {
+                            assert!(v.iter().all(|x| *x == key));
+                        }
+                        let mut last = None;
+                        for (k, _) in map.range(key.., &guard) {
+                            assert!(last < Some(*k) && *k >= key);
+                            last = Some(*k);
+                        }
+                        if map.remove(&((i * 3 + t) % 64)) {
+                            rem += 1;
+                        }
+                    }
+                    (ins, rem)
+                })
+            }).collect();
+            handles.into_iter().map(|h| h.join()).collect()
+        });
+
+        let guard = epoch::pin();
+        let left = map.iter(&guard).count() as isize;
+        let net: isize = counts.iter().map(|&(i, r)| i - r).sum();
+        assert_eq!(left, net);
+        // Every level must have been cleaned of removed nodes
+        for level in 0..MAX_HEIGHT {
+            let mut cur = map.head[level].load(Relaxed);
+            while !cur.is_null() {
+                let (next, marked) = unsafe { (*cur).next[level].load_marked(Relaxed) };
+                assert!(!marked);
+                cur = next;
+            }
+        }
+    }
+
+    #[test]
+    fn test_drops() {
+        let drops = AtomicUsize::new(0);
+        let map = SkipMap::new();
+        for i in 0..100 {
+            assert!(map.insert(i, Counted((), &drops)));
+        }
+        assert!(!map.insert(0, Counted((), &drops)));
+        assert_eq!(drops.load(SeqCst), 1);
+        for i in 0..50 {
+            assert!(map.remove(&i));
+        }
+        flush_until(|| drops.load(SeqCst), 51);
+        drop(map);
+        assert_eq!(drops.load(SeqCst), 101);
+    }
+}
@@ -0,0 +1,277 @@
+//! A Treiber stack
+//!
+//! Pops store_conditional the head instead of cas'ing it, so a node which
+//! was popped and pushed again after the head was linked makes the pop fail
+//! rather than corrupt the stack. That lets nodes be recycled through a free
+//! list instead of freed, and since every node stays allocated for the life
+//! of the stack, reading the next pointer of a stale head is always safe.
+//!
+//! A pop moves the value out of the node, so a reference to the top of the
+//! stack can't outlive a pop by any thread. Peeks borrow the stack mutably
+//! for that reason, which leaves pushes and pops free to never wait.
+//!
+//! ```compile_fail
+//! use exclusive_ptr::collections::Stack;
+//!
+//! let mut stack = Stack::new();
+//! stack.push(1);
+//! let top = stack.peek();
+//! stack.pop();
+//! assert_eq!(top, Some(&1));
+//! ```
+
+use std::ptr;
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, AtomicPtr};
+use std::sync::atomic::Ordering::Relaxed;
+
+use ordering;
+use ExclusivePtr;
+
+pub(super) struct Node<T> {
+    pub(super) val: UnsafeCell<Option<T>>,
+    // Atomic since a stale popper may read it while the node is pushed again
+    pub(super) next: AtomicPtr<Node<T>>,
+}
+
+pub struct Stack<T> {
+    head: ExclusivePtr<Node<T>>,
+    free: ExclusivePtr<Node<T>>,
+    len: AtomicUsize,
+    marker: PhantomData<T>,
+}
+
+/// The values removed by take_all, from the top of the stack down
+pub struct TakeAll<'a, T: 'a> {
+    stack: &'a Stack<T>,
+    cur: *mut Node<T>,
+}
+
+pub(super) fn push_node<T>(list: &ExclusivePtr<Node<T>>, node: *mut Node<T>) {
+    let mut ll = list.load_linked(ordering::Relaxed);
+    loop {
+        unsafe { (*node).next.store(ll.get(), Relaxed) };
+        match ll.store_conditional(node, ordering::Release) {
+            Ok(()) => return,
+            Err(fail) => ll = fail.into_link(),
+        }
+    }
+}
+
+// The next pointer may be rewritten by a push after it is read,
+// but then the store_conditional fails
+pub(super) fn pop_node<T>(list: &ExclusivePtr<Node<T>>) -> *mut Node<T> {
+    let mut ll = list.load_linked(ordering::Acquire);
+    loop {
+        let node = ll.get();
+        if node.is_null() {
+            return node;
+        }
+        let next = unsafe { (*node).next.load(Relaxed) };
+        match ll.store_conditional(next, ordering::Relaxed) {
+            Ok(()) => return node,
+            Err(fail) => ll = fail.into_link(),
+        }
+    }
+}
+
+pub(super) unsafe fn free_list<T>(mut node: *mut Node<T>) {
+    while !node.is_null() {
+        let next = (*node).next.load(Relaxed);
+        drop(Box::from_raw(node));
+        node = next;
+    }
+}
+
+impl<T> Stack<T> {
+
+    pub const fn new() -> Stack<T> {
+        Stack {
+            head: ExclusivePtr::NULL,
+            free: ExclusivePtr::NULL,
+            len: AtomicUsize::new(0),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn push(&self, val: T) {
+        let mut node = pop_node(&self.free);
+        if node.is_null() {
+            node = Box::into_raw(Box::new(Node {
+                val: UnsafeCell::new(None),
+                next: AtomicPtr::new(ptr::null_mut()),
+            }));
+        }
+        unsafe { *(*node).val.get() = Some(val) };
+        // Counted before it's visible, so the estimate never goes below the real length
+        self.len.fetch_add(1, Relaxed);
+        push_node(&self.head, node);
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        let node = pop_node(&self.head);
+        if node.is_null() {
+            return None;
+        }
+        self.len.fetch_sub(1, Relaxed);
+        unsafe { Some(self.recycle(node)) }
+    }
+
+    /// Borrows the top of the stack
+    pub fn peek(&mut self) -> Option<&T> {
+        unsafe {
+            self.head.load(Relaxed).as_ref().map(|node| (*node.val.get()).as_ref().unwrap())
+        }
+    }
+
+    /// Empties the stack in one step
+    pub fn take_all(&self) -> TakeAll<'_, T> {
+        let mut ll = self.head.load_linked(ordering::Acquire);
+        let first = loop {
+            let first = ll.get();
+            if first.is_null() {
+                break first;
+            }
+            match ll.store_conditional(ptr::null_mut(), ordering::Relaxed) {
+                Ok(()) => break first,
+                Err(fail) => ll = fail.into_link(),
+            }
+        };
+        TakeAll {
+            stack: self,
+            cur: first,
+        }
+    }
+
+    /// Estimates the number of values in the stack
+    ///
+    /// This can briefly count values which are still being pushed
+    pub fn len(&self) -> usize {
+        self.len.load(Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Relaxed).is_null()
+    }
+
+    // Takes the value out of an unlinked node and puts the node on the free list
+    unsafe fn recycle(&self, node: *mut Node<T>) -> T {
+        let val = (*(*node).val.get()).take().unwrap();
+        push_node(&self.free, node);
+        val
+    }
+}
+
+impl<T> Default for Stack<T> {
+    fn default() -> Stack<T> {
+        Stack::new()
+    }
+}
+
+impl<T> Drop for Stack<T> {
+    fn drop(&mut self) {
+        unsafe {
+            free_list(self.head.load(Relaxed));
+            free_list(self.free.load(Relaxed));
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for Stack<T> {}
+unsafe impl<T: Send + Sync> Sync for Stack<T> {}
+
+impl<'a, T> Iterator for TakeAll<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        let node = self.cur;
+        if node.is_null() {
+            return None;
+        }
+        unsafe {
+            self.cur = (*node).next.load(Relaxed);
+            self.stack.len.fetch_sub(1, Relaxed);
+            Some(self.stack.recycle(node))
+        }
+    }
+}
+
+impl<'a, T> Drop for TakeAll<'a, T> {
+    fn drop(&mut self) {
+        for _ in self {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate crossbeam;
+    use self::crossbeam::scope;
+    use super::*;
+
+    #[test]
+    fn test_push_pop() {
+        let mut stack = Stack::new();
+        assert!(stack.peek().is_none());
+        for i in 0..10 {
+            stack.push(i);
+        }
+        assert_eq!(stack.len(), 10);
+        assert_eq!(stack.peek(), Some(&9));
+        for i in (0..10).rev() {
+            assert_eq!(stack.pop(), Some(i));
+        }
+        assert_eq!(stack.pop(), None);
+        assert!(stack.is_empty());
+        assert_eq!(stack.len(), 0);
+    }
+
+    #[test]
+    fn test_take_all() {
+        let stack = Stack::new();
+        for i in 0..10 {
+            stack.push(vec![i]);
+        }
+        let taken: Vec<_> = stack.take_all().take(3).collect();
+        assert_eq!(taken, vec![vec![9], vec![8], vec![7]]);
+        // the rest were dropped with the iterator
+        assert!(stack.is_empty());
+        assert_eq!(stack.len(), 0);
+        stack.push(vec![10]);
+        assert_eq!(stack.take_all().collect::<Vec<_>>(), vec![vec![10]]);
+    }
+
+    // Every push reuses a node which was just popped, which is the pattern
+    // that breaks a cas based stack. Any ABA shows up as a lost or duplicated value
+    #[test]
+    fn test_aba_stress() {
+        let num_run: usize = 20000;
+        let num_threads = 4;
+        let stack = Stack::new();
+
+        let popped: Vec<Vec<usize>> = scope(|scope| {
+            let handles: Vec<_> = (0..num_threads).map(|t| {
+                let stack = &stack;
+                scope.spawn(move || {
+                    let mut popped = Vec::new();
+                    for i in 0..num_run {
+                        stack.push(vec![t * num_run + i; 4]);
+                        stack.push(vec![t * num_run + i + num_threads * num_run; 4]);
+                        for _ in 0..2 {
+                            let v = stack.pop().unwrap();
+                            assert!(v.iter().all(|x| *x == v[0]));
+                            popped.push(v[0]);
+                        }
+                    }
+                    popped
+                })
+            }).collect();
+            handles.into_iter().map(|h| h.join()).collect()
+        });
+
+        let mut all: Vec<usize> = popped.into_iter().flatten().collect();
+        all.sort();
+        assert_eq!(all, (0..2 * num_threads * num_run).collect::<Vec<_>>());
+        assert!(stack.is_empty());
+    }
+}
@@ -0,0 +1,332 @@
+//! Flat combining over an ExclusiveData cell
+//!
+//! Instead of every thread running its own load_linked and store_conditional
+//! loop on a hot cell, each thread publishes its operation in a record and
+//! one of them, the combiner, applies every published operation in a single
+//! batch, with one store_conditional for the whole batch. The others wait for
+//! their record to be marked done, taking over as combiner if the lock frees
+//! up first.
+//!
+//! Records are claimed per call like hazard records, so there are only ever
+//! about as many as there are threads applying operations at once. The batch
+//! is still written with a store_conditional, so other writers of the cell
+//! are fine, the batch is just applied again to the new value if one of them
+//! wins. Operations are applied by reference for that reason.
+//!
+//! An operation which panics is skipped, leaving the value as it was, and
+//! the panic is resumed on the thread which published it once the batch is
+//! stored. The rest of the batch is unaffected.
+
+use std::ptr;
+use std::thread;
+use std::panic::{self, AssertUnwindSafe};
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicUsize, AtomicBool};
+use std::sync::atomic::Ordering::{self, Relaxed, Acquire, Release};
+
+use ordering;
+use {ExclusiveData, ExclusivePtr, IsUsize};
+
+// Waiters spin this many times on their record before yielding
+const SPINS: usize = 64;
+
+const IDLE: usize = 0;
+const PENDING: usize = 1;
+const DONE: usize = 2;
+
+/// An operation which can be applied to the value of a FlatCombining cell
+///
+/// The operations a cell takes are the ones its Op type can describe, so
+/// registering a new kind of operation means adding it to Op, which is
+/// usually an enum
+pub trait Operation<T> {
+    type Output;
+
+    /// Returns the new value and the result of applying the operation to val
+    ///
+    /// This may be called more than once if the batch has to be redone,
+    /// only the call on the value which was stored counts
+    fn apply(&self, val: T) -> (T, Self::Output);
+}
+
+struct Record<Op, R> {
+    active: AtomicBool,
+    state: AtomicUsize,
+    // Written by the owner while idle, and by the combiner while pending
+    op: UnsafeCell<Option<Op>>,
+    result: UnsafeCell<Option<thread::Result<R>>>,
+    next: *mut Record<Op, R>,
+}
+
+// Lets the next combiner in, even if the batch panicked
+struct Unlock<'a>(&'a AtomicBool);
+
+pub struct FlatCombining<T: IsUsize, Op: Operation<T>> {
+    cell: ExclusiveData<T>,
+    records: ExclusivePtr<Record<Op, Op::Output>>,
+    combining: AtomicBool,
+    // Only touched by the combiner
+    batch: UnsafeCell<Vec<*const Record<Op, Op::Output>>>,
+    batches: AtomicUsize,
+    combined: AtomicUsize,
+}
+
+impl<T: IsUsize, Op: Operation<T>> FlatCombining<T, Op> {
+
+    pub fn new(cell: ExclusiveData<T>) -> FlatCombining<T, Op> {
+        FlatCombining {
+            cell,
+            records: ExclusivePtr::NULL,
+            combining: AtomicBool::new(false),
+            batch: UnsafeCell::new(Vec::new()),
+            batches: AtomicUsize::new(0),
+            combined: AtomicUsize::new(0),
+        }
+    }
+
+    /// Applies the operation, either in a batch of another thread or as the combiner
+    pub fn apply(&self, op: Op) -> Op::Output {
+        let rec = unsafe { &*self.claim() };
+        unsafe { *rec.op.get() = Some(op) };
+        rec.state.store(PENDING, Release);
+        let mut spins = 0;
+        loop {
+            if rec.state.load(Acquire) == DONE {
+                break;
+            }
+            if !self.combining.load(Relaxed) &&
+               self.combining.compare_exchange(false, true, Acquire, Relaxed).is_ok() {
+                let _unlock = Unlock(&self.combining);
+                unsafe { self.combine() };
+                continue;
+            }
+            spins += 1;
+            if spins % SPINS == 0 {
+                thread::yield_now();
+            }
+        }
+        let result = unsafe { (*rec.result.get()).take().unwrap() };
+        rec.state.store(IDLE, Relaxed);
+        rec.active.store(false, Release);
+        match result {
+            Ok(result) => result,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+
+    pub fn load(&self, ord: Ordering) -> T {
+        self.cell.load(ord)
+    }
+
+    /// Returns the underlying cell, for links which bypass combining
+    pub fn cell(&self) -> &ExclusiveData<T> {
+        &self.cell
+    }
+
+    /// The number of batches applied by combiners
+    pub fn batches(&self) -> usize {
+        self.batches.load(Relaxed)
+    }
+
+    /// The number of operations applied in those batches
+    pub fn combined(&self) -> usize {
+        self.combined.load(Relaxed)
+    }
+
+    // Takes an idle record, or links a new one
+    fn claim(&self) -> *mut Record<Op, Op::Output> {
+        let mut cur = self.records.load(Acquire);
+        while !cur.is_null() {
+            let rec = unsafe { &*cur };
+            if !rec.active.load(Relaxed) &&
+               rec.active.compare_exchange(false, true, Acquire, Relaxed).is_ok() {
+                return cur;
+            }
+            cur = rec.next;
+        }
+
+        let rec = Box::into_raw(Box::new(Record {
+            active: AtomicBool::new(true),
+            state: AtomicUsize::new(IDLE),
+            op: UnsafeCell::new(None),
+            result: UnsafeCell::new(None),
+            next: ptr::null_mut(),
+        }));
+        let mut ll = self.records.load_linked(ordering::Relaxed);
+        loop {
+            unsafe { (*rec).next = ll.get() };
+            match ll.store_conditional(rec, ordering::Release) {
+                Ok(()) => return rec,
+                Err(fail) => ll = fail.into_link(),
+            }
+        }
+    }
+
+    // Applies every pending operation, must only be called by the combiner
+    unsafe fn combine(&self) {
+        let batch = &mut *self.batch.get();
+        batch.clear();
+        let mut cur = self.records.load(Acquire);
+        while !cur.is_null() {
+            if (*cur).state.load(Acquire) == PENDING {
+                batch.push(cur);
+            }
+            cur = (*cur).next;
+        }
+
+        let mut ll = self.cell.load_linked(ordering::Acquire);
+        loop {
+            let mut val = ll.get();
+            for &rec in batch.iter() {
+                let op = (*(*rec).op.get()).as_ref().unwrap();
+                let prev = val.to_usize();
+                val = match panic::catch_unwind(AssertUnwindSafe(|| op.apply(val))) {
+                    Ok((next, result)) => {
+                        *(*rec).result.get() = Some(Ok(result));
+                        next
+                    },
+                    Err(payload) => {
+                        *(*rec).result.get() = Some(Err(payload));
+                        T::from_usize(prev)
+                    },
+                };
+            }
+            match ll.store_conditional(val, ordering::Release) {
+                Ok(()) => break,
+                Err(fail) => ll = fail.into_link(),
+            }
+        }
+
+        for &rec in batch.iter() {
+            *(*rec).op.get() = None;
+            (*rec).state.store(DONE, Release);
+        }
+        self.batches.fetch_add(1, Relaxed);
+        self.combined.fetch_add(batch.len(), Relaxed);
+    }
+}
+
+impl<'a> Drop for Unlock<'a> {
+    fn drop(&mut self) {
+        self.0.store(false, Release);
+    }
+}
+
+impl<T: IsUsize, Op: Operation<T>> Drop for FlatCombining<T, Op> {
+    fn drop(&mut self) {
+        let mut cur = self.records.load(Relaxed);
+        while !cur.is_null() {
+            let rec = unsafe { Box::from_raw(cur) };
+            cur = rec.next;
+        }
+    }
+}
+
+unsafe impl<T: IsUsize, Op: Operation<T> + Send> Send for FlatCombining<T, Op>
+    where Op::Output: Send {}
+unsafe impl<T: IsUsize, Op: Operation<T> + Send> Sync for FlatCombining<T, Op>
+    where Op::Output: Send {}
+
+#[cfg(test)]
+mod test {
+    use crossbeam::scope;
+    use super::*;
+    use std::panic;
+    use ExclusiveUsize;
+
+    enum Counter {
+        Add(usize),
+        Sub(usize),
+        Get,
+        Panic,
+    }
+
+    impl Operation<usize> for Counter {
+        type Output = usize;
+
+        // Returns the value from before the operation
+        fn apply(&self, val: usize) -> (usize, usize) {
+            match *self {
+                Counter::Add(n) => (val + n, val),
+                Counter::Sub(n) => (val - n, val),
+                Counter::Get => (val, val),
+                Counter::Panic => panic!("operation failed"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let counter = FlatCombining::new(ExclusiveUsize::new(5));
+        assert_eq!(counter.apply(Counter::Add(3)), 5);
+        assert_eq!(counter.apply(Counter::Sub(2)), 8);
+        assert_eq!(counter.apply(Counter::Get), 6);
+        assert_eq!(counter.batches(), 3);
+        assert_eq!(counter.combined(), 3);
+        assert_eq!(counter.load(Relaxed), 6);
+    }
+
+    #[test]
+    fn test_panic() {
+        let counter = FlatCombining::new(ExclusiveUsize::new(5));
+        let res = panic::catch_unwind(panic::AssertUnwindSafe(|| counter.apply(Counter::Panic)));
+        assert!(res.is_err());
+        assert_eq!(counter.apply(Counter::Add(1)), 5);
+        assert_eq!(counter.load(Relaxed), 6);
+
+        scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for i in 0..1000 {
+                        let fail = i % 100 == 0;
+                        let op = if fail { Counter::Panic } else { Counter::Add(1) };
+                        let res = panic::catch_unwind(panic::AssertUnwindSafe(|| counter.apply(op)));
+                        // Only the thread whose operation failed panics
+                        assert_eq!(res.is_err(), fail);
+                    }
+                });
+            }
+        });
+        assert_eq!(counter.load(Relaxed), 6 + 4 * 990);
+    }
+
+    // Other threads store straight to the cell while the combiners run,
+    // so batches have to be redone on the new value without losing any
+    #[test]
+    fn test_mt_combine() {
+        let num_run: usize = 20000;
+        let num_threads = 4;
+        let counter = FlatCombining::new(ExclusiveUsize::new(0));
+
+        let seen: Vec<Vec<usize>> = scope(|scope| {
+            let direct = &counter;
+            scope.spawn(move || {
+                for _ in 0..num_run {
+                    let mut ll = direct.cell().load_linked(ordering::Relaxed);
+                    loop {
+                        let val = ll.get() + 1;
+                        match ll.store_conditional(val, ordering::Relaxed) {
+                            Ok(()) => break,
+                            Err(fail) => ll = fail.into_link(),
+                        }
+                    }
+                }
+            });
+            let handles: Vec<_> = (0..num_threads).map(|_| {
+                let counter = &counter;
+                scope.spawn(move || {
+                    (0..num_run).map(|_| counter.apply(Counter::Add(2))).collect::<Vec<_>>()
+                })
+            }).collect();
+            handles.into_iter().map(|h| h.join()).collect()
+        });
+
+        let total = num_run + 2 * num_threads * num_run;
+        assert_eq!(counter.load(Relaxed), total);
+        assert_eq!(counter.combined(), num_threads * num_run);
+        for s in &seen {
+            // Every add of a thread saw the value its last add left, or later
+            assert!(s.windows(2).all(|w| w[0] + 2 <= w[1]));
+        }
+    }
+}
@@ -0,0 +1,171 @@
+//! A counter sharded over cache lines
+//!
+//! Every thread adds to the shard picked by its thread index, so threads only
+//! contend on a shard when there are more of them than shards. Each shard sits
+//! on its own cache line, so adds to different shards don't fight over the
+//! line either, and on LL/SC hardware they don't break each other's links.
+//!
+//! Shards only ever count up, and add panics rather than let one wrap, so the
+//! value of a shard doubles as its version. The link counter can't be used
+//! for that instead, since powerpc doesn't keep one.
+//! get just adds up the shards, which may mix values from different moments.
+//! sum collects the shards until two collects in a row match: every shard then
+//! held its value from its first read to its second, so all of them held
+//! their values at once between the two collects, and the total is one the
+//! counter really had. Threads adding nonstop could keep that from ever
+//! happening, so sum gives up after a few collects and returns the last one.
+//! Since shards only count up, that total still lies between the counts at
+//! the start and end of the collect.
+
+use std::mem;
+use std::sync::atomic::Ordering::{Relaxed, SeqCst};
+
+use ordering;
+use thread_index::thread_index;
+use {CachePadded, ExclusiveUsize};
+
+const DEFAULT_SHARDS: usize = 16;
+
+// Collects sum makes after the first one before settling for the last
+const SUM_ATTEMPTS: usize = 8;
+
+pub struct ShardedCounter {
+    shards: Box<[CachePadded<ExclusiveUsize>]>,
+}
+
+impl ShardedCounter {
+
+    pub fn new() -> ShardedCounter {
+        ShardedCounter::with_shards(DEFAULT_SHARDS)
+    }
+
+    /// Panics if num_shards is 0
+    pub fn with_shards(num_shards: usize) -> ShardedCounter {
+        assert!(num_shards > 0, "there must be at least one shard");
+        ShardedCounter {
+            shards: (0..num_shards).map(|_| CachePadded::new(ExclusiveUsize::new(0)))
+                                   .collect::<Vec<_>>().into_boxed_slice(),
+        }
+    }
+
+    /// Panics if the shard of the current thread would overflow
+    pub fn add(&self, n: usize) {
+        let shard = &self.shards[thread_index() % self.shards.len()];
+        let mut ll = shard.load_linked(ordering::Relaxed);
+        loop {
+            let val = ll.get().checked_add(n).expect("counter shard overflowed");
+            match ll.store_conditional(val, ordering::SeqCst) {
+                Ok(()) => return,
+                Err(fail) => ll = fail.into_link(),
+            }
+        }
+    }
+
+    /// Returns the total of the shards, which may be stale or never have
+    /// been the count at all if other threads are adding
+    pub fn get(&self) -> usize {
+        self.shards.iter().fold(0, |sum, s| sum.wrapping_add(s.load(Relaxed)))
+    }
+
+    /// Returns a total the counter had at some point during the call
+    ///
+    /// If other threads keep adding through every collect, this is instead
+    /// the last collect, which is no less than the count when it started
+    /// and no more than the count when it ended
+    pub fn sum(&self) -> usize {
+        let mut prev = Vec::with_capacity(self.shards.len());
+        let mut next = Vec::with_capacity(self.shards.len());
+        self.collect(&mut prev);
+        for _ in 0..SUM_ATTEMPTS {
+            self.collect(&mut next);
+            if next == prev {
+                break;
+            }
+            mem::swap(&mut prev, &mut next);
+        }
+        // Either both collects match or prev is the latest
+        prev.iter().fold(0, |sum, &n| sum.wrapping_add(n))
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn collect(&self, into: &mut Vec<usize>) {
+        into.clear();
+        into.extend(self.shards.iter().map(|s| s.load(SeqCst)));
+    }
+}
+
+impl Default for ShardedCounter {
+    fn default() -> ShardedCounter {
+        ShardedCounter::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crossbeam::scope;
+    use std::sync::atomic::AtomicBool;
+    use super::*;
+
+    #[test]
+    fn test_add_sum() {
+        let counter = ShardedCounter::with_shards(4);
+        assert_eq!(counter.sum(), 0);
+        for i in 0..10 {
+            counter.add(i);
+        }
+        assert_eq!(counter.get(), 45);
+        assert_eq!(counter.sum(), 45);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_overflow() {
+        let counter = ShardedCounter::with_shards(1);
+        counter.add(usize::MAX);
+        counter.add(1);
+    }
+
+    // Fewer shards than threads, so shards are shared as well. Once an add
+    // returns every sum has to include it, and a reader never sees the
+    // total go backwards.
+    #[test]
+    fn test_mt_sum() {
+        let num_run: usize = 20000;
+        let num_threads = 4;
+        let counter = ShardedCounter::with_shards(3);
+        let done = AtomicBool::new(false);
+
+        scope(|scope| {
+            let (counter, done) = (&counter, &done);
+            let reader = scope.spawn(move || {
+                let mut last = 0;
+                while !done.load(SeqCst) {
+                    let sum = counter.sum();
+                    assert!(sum >= last);
+                    last = sum;
+                }
+            });
+            let handles: Vec<_> = (0..num_threads).map(|_| {
+                scope.spawn(move || {
+                    for i in 1..num_run + 1 {
+                        counter.add(1);
+                        if i % 1000 == 0 {
+                            assert!(counter.sum() >= i);
+                        }
+                    }
+                })
+            }).collect();
+            for h in handles {
+                h.join();
+            }
+            done.store(true, SeqCst);
+            reader.join();
+        });
+
+        assert_eq!(counter.sum(), num_threads * num_run);
+        assert_eq!(counter.get(), num_threads * num_run);
+    }
+}
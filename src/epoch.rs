@@ -0,0 +1,361 @@
+//! Epoch based reclamation which runs destructors, for the collections
+//!
+//! crossbeam 0.2's epoch only frees the memory of unlinked values and never
+//! drops them, so anything owning heap memory or resources leaks. The
+//! collections and universal constructions hold arbitrary values, so they
+//! retire them here instead, where they are dropped as the Box they came from.
+//! Only pin and Guard are exported, to pin around reads of those structures.
+//!
+//! A thread pins itself to the global epoch while it reads. The epoch only
+//! advances once every pinned thread has caught up with it, so values retired
+//! in one epoch are destroyed after it has advanced twice more, by which point
+//! every thread which could have seen them has unpinned.
+//!
+//! Each thread keeps its own bag of retired values, which is collected every
+//! so often when it pins. A thread which exits hands its bag over to a global
+//! list of orphans, collected by whichever thread comes along next. Its
+//! record is reused by a later thread, and like hazard records never freed.
+
+use std::mem;
+use std::ptr;
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicUsize, AtomicBool, fence};
+use std::sync::atomic::Ordering::{Relaxed, Acquire, Release, SeqCst};
+
+use ordering;
+use ExclusivePtr;
+
+// Pins between attempts to advance the epoch and collect the bag
+const COLLECT_EVERY: usize = 128;
+
+// The epoch counts up in steps of two, leaving the low bit of a
+// record's epoch to say whether it is pinned
+const PINNED: usize = 1;
+const STEP: usize = 2;
+
+static EPOCH: AtomicUsize = AtomicUsize::new(0);
+static RECORDS: ExclusivePtr<Record> = ExclusivePtr::NULL;
+static ORPHANS: ExclusivePtr<Orphans> = ExclusivePtr::NULL;
+
+thread_local!(static HANDLE: Handle = Handle { record: acquire() });
+
+struct Garbage {
+    epoch: usize,
+    ptr: *mut u8,
+    destroy: unsafe fn(*mut u8),
+}
+
+struct State {
+    guards: usize,
+    pins: usize,
+    // Set once the thread local handle is gone, so the last guard releases the record
+    detached: bool,
+    bag: Vec<Garbage>,
+}
+
+struct Record {
+    epoch: AtomicUsize,
+    active: AtomicBool,
+    next: *mut Record,
+    // Only touched by whoever holds the record active
+    state: UnsafeCell<State>,
+}
+
+struct Orphans {
+    bag: Vec<Garbage>,
+    next: *mut Orphans,
+}
+
+struct Handle {
+    record: *const Record,
+}
+
+/// Keeps the current thread pinned, so nothing it loads is destroyed
+///
+/// Guards can be nested, the thread stays pinned until the last one is dropped
+pub struct Guard {
+    record: *const Record,
+}
+
+unsafe fn destroy_box<T>(ptr: *mut u8) {
+    drop(Box::from_raw(ptr as *mut T));
+}
+
+// Takes a record, reusing an idle one when possible
+fn acquire() -> *const Record {
+    let mut cur = RECORDS.load(Acquire);
+    while !cur.is_null() {
+        let rec = unsafe { &*cur };
+        if !rec.active.load(Relaxed) &&
+           rec.active.compare_exchange(false, true, Acquire, Relaxed).is_ok() {
+            return rec;
+        }
+        cur = rec.next;
+    }
+
+    let rec = Box::into_raw(Box::new(Record {
+        epoch: AtomicUsize::new(0),
+        active: AtomicBool::new(true),
+        next: ptr::null_mut(),
+        state: UnsafeCell::new(State {
+            guards: 0,
+            pins: 0,
+            detached: false,
+            bag: Vec::new(),
+        }),
+    }));
+    let mut ll = RECORDS.load_linked(ordering::Relaxed);
+    loop {
+        unsafe { (*rec).next = ll.get() };
+        match ll.store_conditional(rec, ordering::Release) {
+            Ok(()) => return rec,
+            Err(fail) => ll = fail.into_link(),
+        }
+    }
+}
+
+// Hands the bag over to the orphans and makes the record available again
+unsafe fn release(rec: *const Record) {
+    let bag = mem::take(&mut (*(*rec).state.get()).bag);
+    if !bag.is_empty() {
+        let orphans = Box::into_raw(Box::new(Orphans {
+            bag,
+            next: ptr::null_mut(),
+        }));
+        let mut ll = ORPHANS.load_linked(ordering::Relaxed);
+        loop {
+            (*orphans).next = ll.get();
+            match ll.store_conditional(orphans, ordering::Release) {
+                Ok(()) => break,
+                Err(fail) => ll = fail.into_link(),
+            }
+        }
+    }
+    (*(*rec).state.get()).detached = false;
+    (*rec).active.store(false, Release);
+}
+
+// Advances the epoch if every pinned record has caught up with it
+fn try_advance() {
+    let global = EPOCH.load(Relaxed);
+    // Pairs with the fence in pin, so a record which pinned before this
+    // is seen pinned, and one which pins after sees the new epoch
+    fence(SeqCst);
+    let mut cur = RECORDS.load(Acquire);
+    while !cur.is_null() {
+        let rec = unsafe { &*cur };
+        let epoch = rec.epoch.load(Relaxed);
+        if epoch & PINNED != 0 && epoch & !PINNED != global {
+            return;
+        }
+        cur = rec.next;
+    }
+    // Whatever the unpinned records read happens before the advance
+    fence(Acquire);
+    let _ = EPOCH.compare_exchange(global, global.wrapping_add(STEP), Release, Relaxed);
+}
+
+// Destroys whatever in the bag and the orphans is old enough
+unsafe fn collect(rec: *const Record) {
+    let mut bag = mem::take(&mut (*(*rec).state.get()).bag);
+    let mut ll = ORPHANS.load_linked(ordering::Acquire);
+    while !ll.get().is_null() {
+        let head = ll.get();
+        match ll.store_conditional(ptr::null_mut(), ordering::Relaxed) {
+            Ok(()) => {
+                let mut cur = head;
+                while !cur.is_null() {
+                    let orphans = Box::from_raw(cur);
+                    bag.extend(orphans.bag);
+                    cur = orphans.next;
+                }
+                break;
+            },
+            Err(fail) => ll = fail.into_link(),
+        }
+    }
+
+    let global = EPOCH.load(Acquire);
+    let mut kept = Vec::new();
+    // The bag is out of the record while destructors run,
+    // since they may pin and retire values themselves
+    for garbage in bag {
+        if global.wrapping_sub(garbage.epoch) >= 2 * STEP {
+            (garbage.destroy)(garbage.ptr);
+        } else {
+            kept.push(garbage);
+        }
+    }
+    let state = &mut *(*rec).state.get();
+    kept.append(&mut state.bag);
+    state.bag = kept;
+}
+
+/// Pins the current thread until the guard is dropped
+pub fn pin() -> Guard {
+    let rec = HANDLE.try_with(|handle| handle.record).unwrap_or_else(|_| {
+        // The thread is exiting, so pin on a record of its own
+        let rec = acquire();
+        unsafe { (*(*rec).state.get()).detached = true };
+        rec
+    });
+    unsafe {
+        let state = &mut *(*rec).state.get();
+        state.guards += 1;
+        if state.guards == 1 {
+            (*rec).epoch.store(EPOCH.load(Relaxed) | PINNED, Relaxed);
+            // Orders the pin before every load made under it
+            fence(SeqCst);
+            state.pins = state.pins.wrapping_add(1);
+            if state.pins.is_multiple_of(COLLECT_EVERY) {
+                try_advance();
+                collect(rec);
+            }
+        }
+    }
+    Guard { record: rec }
+}
+
+/// Advances the epoch as far as it will go, and destroys everything
+/// retired by this thread or by exited threads which is old enough
+///
+/// Any guard held anywhere keeps the epoch from advancing, so this
+/// does little while the current thread is pinned
+#[cfg(test)]
+pub(crate) fn flush() {
+    match HANDLE.try_with(|handle| handle.record) {
+        Ok(rec) => unsafe {
+            try_advance();
+            try_advance();
+            collect(rec);
+        },
+        Err(_) => unsafe {
+            let rec = acquire();
+            try_advance();
+            try_advance();
+            collect(rec);
+            release(rec);
+        },
+    }
+}
+
+impl Guard {
+
+    /// Drops the value once no thread can still be reading it
+    ///
+    /// # Safety
+    ///
+    /// The pointer must have come from Box::into_raw, must already be
+    /// unreachable from every shared structure, and must not be retired twice.
+    /// It may be dropped on any thread, so T must be safe to send.
+    pub(crate) unsafe fn retire<T>(&self, ptr: *mut T) {
+        if ptr.is_null() {
+            return;
+        }
+        // Orders the unlinking store before the epoch is read,
+        // so the stamp is no older than any reader which saw the value
+        fence(SeqCst);
+        let state = &mut *(*self.record).state.get();
+        state.bag.push(Garbage {
+            epoch: EPOCH.load(Relaxed),
+            ptr: ptr as *mut u8,
+            destroy: destroy_box::<T>,
+        });
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        unsafe {
+            let state = &mut *(*self.record).state.get();
+            state.guards -= 1;
+            if state.guards == 0 {
+                (*self.record).epoch.store(0, Release);
+                if state.detached {
+                    release(self.record);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        unsafe {
+            let state = &mut *(*self.record).state.get();
+            // A guard outliving the handle releases the record itself
+            match state.guards {
+                0 => release(self.record),
+                _ => state.detached = true,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crossbeam::scope;
+    use super::*;
+    use test_util::{Counted, flush_until};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::{Relaxed, SeqCst};
+
+    #[test]
+    fn test_retire() {
+        let drops = AtomicUsize::new(0);
+        let cell = ExclusivePtr::new(Box::into_raw(Box::new(Counted(vec![1], &drops))));
+        {
+            let reader = pin();
+            let old = cell.load(Relaxed);
+            let new = Box::into_raw(Box::new(Counted(vec![2], &drops)));
+            cell.load_linked(Relaxed).store_conditional(new, Relaxed).unwrap();
+            unsafe { reader.retire(old) };
+            flush();
+            // Still pinned by this thread
+            assert_eq!(drops.load(SeqCst), 0);
+            assert_eq!(unsafe { &(*old).0 }, &vec![1]);
+            // Nested guards don't unpin
+            drop(pin());
+            flush();
+            assert_eq!(drops.load(SeqCst), 0);
+        }
+        flush_until(|| drops.load(SeqCst), 1);
+        unsafe { pin().retire(cell.load(Relaxed)) };
+        flush_until(|| drops.load(SeqCst), 2);
+    }
+
+    #[test]
+    fn test_mt_replace() {
+        let num_run: usize = 10000;
+        let drops = AtomicUsize::new(0);
+        let cell = ExclusivePtr::new(Box::into_raw(Box::new(Counted(vec![0; 16], &drops))));
+
+        scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for i in 0..num_run {
+                        let guard = pin();
+                        let new = Box::into_raw(Box::new(Counted(vec![i; 16], &drops)));
+                        let mut ll = cell.load_linked(Relaxed);
+                        loop {
+                            let v = unsafe { &(*ll.get()).0 };
+                            assert!(v.iter().all(|x| *x == v[0]));
+                            let old = ll.get();
+                            match ll.store_conditional(new, Relaxed) {
+                                Ok(()) => {
+                                    unsafe { guard.retire(old) };
+                                    break;
+                                },
+                                Err(fail) => ll = fail.into_link(),
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        // The threads have exited, so their garbage is with the orphans
+        unsafe { pin().retire(cell.load(Relaxed)) };
+        flush_until(|| drops.load(SeqCst), 4 * num_run + 1);
+    }
+}
@@ -0,0 +1,183 @@
+//! An Arc which can be swapped atomically
+//!
+//! Cloning an Arc read out of an ExclusivePtr races with the last owner
+//! dropping it, since the count is bumped after the pointer is read.
+//! ExclusiveArc stores the pointer from Arc::into_raw directly, and loads bump
+//! the count under a grace period pin. A replaced Arc keeps its reference
+//! until every load which might still be bumping it has unpinned, which is
+//! handled by retiring it rather than waiting. Neither loads nor stores ever
+//! wait, and a load only goes through the one pointer.
+
+use std::ptr;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::sync::atomic::Ordering::{Relaxed, Acquire, SeqCst};
+
+use ordering;
+use grace::Grace;
+use ExclusivePtr;
+
+pub struct ExclusiveArc<T> {
+    ptr: ExclusivePtr<T>,
+    grace: Grace<Arc<T>>,
+    marker: PhantomData<Arc<T>>,
+}
+
+impl<T> ExclusiveArc<T> {
+
+    pub fn new(val: Arc<T>) -> ExclusiveArc<T> {
+        ExclusiveArc {
+            ptr: ExclusivePtr::new(Arc::into_raw(val) as *mut T),
+            grace: Grace::new(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Returns a new reference to the current value
+    pub fn load(&self) -> Arc<T> {
+        let _pin = self.grace.pin();
+        let cur = self.ptr.load(Acquire);
+        // The cell's reference can't be released while we are pinned
+        unsafe {
+            Arc::increment_strong_count(cur);
+            Arc::from_raw(cur)
+        }
+    }
+
+    pub fn store(&self, val: Arc<T>) {
+        self.swap(val);
+    }
+
+    /// Stores a new value, returning the old one
+    pub fn swap(&self, val: Arc<T>) -> Arc<T> {
+        let old = self.ptr.exchange_direct(Arc::into_raw(val) as *mut T, SeqCst);
+        self.retire(old)
+    }
+
+    /// Stores new if the cell still holds current, compared by pointer
+    ///
+    /// On success, returns the replaced value, otherwise hands new back.
+    /// The check and store go through a link, so this only fails
+    /// when the cell really holds a different Arc
+    pub fn compare_and_swap(&self, current: &Arc<T>, new: Arc<T>) -> Result<Arc<T>, Arc<T>> {
+        let new = Arc::into_raw(new) as *mut T;
+        let mut ll = self.ptr.load_linked(ordering::Acquire);
+        loop {
+            let old = ll.get();
+            if !ptr::eq(old, Arc::as_ptr(current)) {
+                return Err(unsafe { Arc::from_raw(new) });
+            }
+            match ll.store_conditional(new, ordering::SeqCst) {
+                Ok(()) => return Ok(self.retire(old)),
+                Err(fail) => ll = fail.into_link(),
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> Arc<T> {
+        let old = self.ptr.exchange_direct(ptr::null_mut(), Relaxed);
+        unsafe { Arc::from_raw(old) }
+    }
+
+    // Takes over the cell's reference to a replaced value, handing one back
+    // and keeping the other until no load can be bumping the count
+    fn retire(&self, old: *mut T) -> Arc<T> {
+        let old = unsafe { Arc::from_raw(old) };
+        self.grace.retire(old.clone());
+        old
+    }
+}
+
+impl<T> Drop for ExclusiveArc<T> {
+    fn drop(&mut self) {
+        let cur = self.ptr.load(Relaxed);
+        if !cur.is_null() {
+            unsafe { drop(Arc::from_raw(cur)) };
+        }
+    }
+}
+
+impl<T: Default> Default for ExclusiveArc<T> {
+    fn default() -> ExclusiveArc<T> {
+        ExclusiveArc::new(Arc::new(T::default()))
+    }
+}
+
+unsafe impl<T: Send + Sync> Send for ExclusiveArc<T> {}
+unsafe impl<T: Send + Sync> Sync for ExclusiveArc<T> {}
+
+#[cfg(test)]
+mod test {
+    extern crate crossbeam;
+    use self::crossbeam::scope;
+    use super::*;
+
+    #[test]
+    fn test_swap_load() {
+        let first = Arc::new(1);
+        let cell = ExclusiveArc::new(first.clone());
+        let loaded = cell.load();
+        assert!(Arc::ptr_eq(&loaded, &first));
+        assert_eq!(Arc::strong_count(&first), 3);
+
+        let old = cell.swap(Arc::new(2));
+        assert!(Arc::ptr_eq(&old, &first));
+        drop(old);
+        drop(loaded);
+        assert_eq!(Arc::strong_count(&first), 1);
+
+        cell.store(Arc::new(3));
+        assert_eq!(*cell.load(), 3);
+        assert_eq!(Arc::strong_count(&cell.into_inner()), 1);
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let cell = ExclusiveArc::new(Arc::new(1));
+        let cur = cell.load();
+        let stale = Arc::new(1);
+        assert_eq!(*cell.compare_and_swap(&stale, Arc::new(2)).unwrap_err(), 2);
+        let old = cell.compare_and_swap(&cur, Arc::new(3)).unwrap();
+        assert!(Arc::ptr_eq(&old, &cur));
+        assert!(cell.compare_and_swap(&cur, Arc::new(4)).is_err());
+        assert_eq!(*cell.load(), 3);
+    }
+
+    #[test]
+    fn test_mt_load_store() {
+        let num_run: usize = 10000;
+        let cell = ExclusiveArc::new(Arc::new(vec![0usize; 16]));
+
+        scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..num_run {
+                        let v = cell.load();
+                        assert!(v.iter().all(|x| *x == v[0]));
+                    }
+                });
+            }
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    for i in 0..num_run / 10 {
+                        let mut cur = cell.load();
+                        loop {
+                            let next = Arc::new(vec![cur[0] + 1; 16]);
+                            match cell.compare_and_swap(&cur, next) {
+                                Ok(_) => break,
+                                Err(_) => cur = cell.load(),
+                            }
+                        }
+                        if i % 2 == 0 {
+                            cell.store(cell.load());
+                        }
+                    }
+                });
+            }
+        });
+
+        let last = cell.into_inner();
+        assert_eq!(Arc::strong_count(&last), 1);
+        assert_eq!(last[0], 2 * (num_run / 10));
+    }
+}
@@ -0,0 +1,252 @@
+//! A fixed size array of cells with a chosen spacing
+//!
+//! A Vec of cells packs them together, so neighbours share cache lines and,
+//! on LL/SC hardware, reservation granules, and a store to one of them breaks
+//! links on the others. An ExclusiveArray spaces its cells out by the padding
+//! it was created with, which trades memory for cells that behave the same no
+//! matter how busy their neighbours are.
+
+use std::alloc::{self, Layout};
+use std::cmp;
+use std::mem;
+use std::marker::PhantomData;
+use std::ops::Index;
+use std::ptr;
+
+use cache_padded::{CACHE_LINE, RESERVATION_GRANULE};
+use {ExclusiveData, IsUsize};
+
+/// How far apart the cells of an ExclusiveArray are placed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Padding {
+    /// Cells are packed together like in a Vec
+    None,
+
+    /// Each cell gets its own cache line
+    CacheLine,
+
+    /// Each cell gets its own reservation granule, which is the
+    /// architectural maximum and can be far larger than a cache line
+    Granule,
+}
+
+impl Padding {
+
+    fn bytes(self) -> usize {
+        match self {
+            Padding::None => 0,
+            Padding::CacheLine => CACHE_LINE,
+            Padding::Granule => RESERVATION_GRANULE,
+        }
+    }
+}
+
+pub struct ExclusiveArray<T: IsUsize> {
+    cells: *mut u8,
+    len: usize,
+    stride: usize,
+    padding: Padding,
+    marker: PhantomData<ExclusiveData<T>>,
+}
+
+impl<T: IsUsize> ExclusiveArray<T> {
+
+    /// Creates an array of len cells, each holding f of its index
+    pub fn from_fn<F>(len: usize, padding: Padding, mut f: F) -> ExclusiveArray<T>
+        where F: FnMut(usize) -> T {
+        let (layout, stride) = ExclusiveArray::<T>::layout(len, padding);
+        let cells = match layout.size() {
+            0 => layout.align() as *mut u8,
+            _ => unsafe { alloc::alloc(layout) },
+        };
+        if cells.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        for i in 0..len {
+            unsafe {
+                ptr::write(cells.add(i * stride) as *mut ExclusiveData<T>,
+                           ExclusiveData::from_value(f(i)));
+            }
+        }
+        ExclusiveArray {
+            cells,
+            len,
+            stride,
+            padding,
+            marker: PhantomData,
+        }
+    }
+
+    /// Creates an array of len cells, each holding val
+    pub fn filled(len: usize, padding: Padding, val: T) -> ExclusiveArray<T>
+        where T: Copy {
+        ExclusiveArray::from_fn(len, padding, |_| val)
+    }
+
+    pub fn get(&self, index: usize) -> Option<&ExclusiveData<T>> {
+        match index < self.len {
+            true => unsafe {
+                Some(&*(self.cells.add(index * self.stride) as *const ExclusiveData<T>))
+            },
+            false => None,
+        }
+    }
+
+    pub fn iter(&self) -> ArrayIter<'_, T> {
+        ArrayIter {
+            array: self,
+            index: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn padding(&self) -> Padding {
+        self.padding
+    }
+
+    /// The distance in bytes from one cell to the next
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    // Returns the layout of the whole array and the stride of its cells
+    fn layout(len: usize, padding: Padding) -> (Layout, usize) {
+        let align = cmp::max(mem::align_of::<ExclusiveData<T>>(), padding.bytes());
+        let stride = (mem::size_of::<ExclusiveData<T>>() + align - 1) & !(align - 1);
+        let size = len.checked_mul(stride).expect("array size overflows usize");
+        (Layout::from_size_align(size, align).unwrap(), stride)
+    }
+}
+
+impl<T: IsUsize> Index<usize> for ExclusiveArray<T> {
+    type Output = ExclusiveData<T>;
+
+    fn index(&self, index: usize) -> &ExclusiveData<T> {
+        match self.get(index) {
+            Some(cell) => cell,
+            None => panic!("index {} out of bounds for an array of {} cells", index, self.len),
+        }
+    }
+}
+
+impl<T: IsUsize> Drop for ExclusiveArray<T> {
+    fn drop(&mut self) {
+        let (layout, _) = ExclusiveArray::<T>::layout(self.len, self.padding);
+        unsafe {
+            for i in 0..self.len {
+                ptr::drop_in_place(self.cells.add(i * self.stride) as *mut ExclusiveData<T>);
+            }
+            if layout.size() != 0 {
+                alloc::dealloc(self.cells, layout);
+            }
+        }
+    }
+}
+
+unsafe impl<T: IsUsize> Send for ExclusiveArray<T> {}
+unsafe impl<T: IsUsize> Sync for ExclusiveArray<T> {}
+
+pub struct ArrayIter<'a, T: 'a + IsUsize> {
+    array: &'a ExclusiveArray<T>,
+    index: usize,
+}
+
+impl<'a, T: IsUsize> Iterator for ArrayIter<'a, T> {
+    type Item = &'a ExclusiveData<T>;
+
+    fn next(&mut self) -> Option<&'a ExclusiveData<T>> {
+        let cell = self.array.get(self.index);
+        if cell.is_some() {
+            self.index += 1;
+        }
+        cell
+    }
+}
+
+impl<'a, T: IsUsize> IntoIterator for &'a ExclusiveArray<T> {
+    type Item = &'a ExclusiveData<T>;
+    type IntoIter = ArrayIter<'a, T>;
+
+    fn into_iter(self) -> ArrayIter<'a, T> {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crossbeam::scope;
+    use super::*;
+    use std::sync::atomic::Ordering::Relaxed;
+    use ordering;
+
+    #[test]
+    fn test_from_fn() {
+        let array = ExclusiveArray::from_fn(10, Padding::None, |i: usize| i * 2);
+        assert_eq!(array.len(), 10);
+        assert_eq!(array[3].load(Relaxed), 6);
+        assert!(array.get(10).is_none());
+        let ll = array[9].load_linked(ordering::Relaxed);
+        assert!(ll.try_store_conditional(1, ordering::Relaxed));
+        assert_eq!(array.iter().map(|c| c.load(Relaxed)).sum::<usize>(), 73);
+
+        let empty = ExclusiveArray::filled(0, Padding::Granule, false);
+        assert!(empty.is_empty());
+        assert!(empty.iter().next().is_none());
+    }
+
+    #[test]
+    fn test_stride() {
+        let size = mem::size_of::<ExclusiveData<usize>>();
+        for &(padding, stride) in &[(Padding::None, size),
+                                    (Padding::CacheLine, CACHE_LINE),
+                                    (Padding::Granule, RESERVATION_GRANULE)] {
+            let array = ExclusiveArray::filled(4, padding, 0usize);
+            assert_eq!(array.stride(), stride);
+            for (i, cell) in array.iter().enumerate() {
+                let addr = cell as *const _ as usize;
+                assert_eq!(addr - (&array[0] as *const _ as usize), i * stride);
+                if padding != Padding::None {
+                    assert_eq!(addr % stride, 0);
+                }
+            }
+        }
+    }
+
+    // Each thread has its own cell, so whatever breaks a link
+    // it can never be another value being stored
+    #[test]
+    fn test_mt_slots() {
+        let num_run: usize = 20000;
+        let num_threads = 4;
+        let array = ExclusiveArray::filled(num_threads, Padding::Granule, 0usize);
+
+        scope(|scope| {
+            for t in 0..num_threads {
+                let cell = &array[t];
+                scope.spawn(move || {
+                    for _ in 0..num_run {
+                        let mut ll = cell.load_linked(ordering::Relaxed);
+                        loop {
+                            let val = ll.get() + 1;
+                            match ll.store_conditional(val, ordering::Relaxed) {
+                                Ok(()) => break,
+                                Err(fail) => {
+                                    assert!(fail.value_unchanged());
+                                    ll = fail.into_link();
+                                },
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        assert!(array.iter().all(|c| c.load(Relaxed) == num_run));
+    }
+}
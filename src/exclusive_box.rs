@@ -0,0 +1,287 @@
+//! An owning version of ExclusivePtr
+//!
+//! ExclusiveBox holds a Box<T> instead of a raw pointer. Readers pin the cell
+//! to borrow the current value, and a displaced box is dropped once every
+//! reader which might still see it has unpinned. That is a grace period, like
+//! in userspace rcu, but nobody waits for it: the box is retired to the cell,
+//! and dropped by a later write once it is safe. Neither readers nor writers
+//! ever wait, so a thread may store while it holds pins on the same cell.
+
+use std::ptr;
+use std::marker::PhantomData;
+use std::sync::atomic::Ordering::{Relaxed, Acquire, SeqCst};
+
+use ordering;
+use grace::{Grace, GracePin};
+use {ExclusivePtr, LinkedPtr, FailureReason};
+
+pub struct ExclusiveBox<T> {
+    ptr: ExclusivePtr<T>,
+    grace: Grace<Box<T>>,
+    marker: PhantomData<Box<T>>,
+}
+
+/// A pin on an ExclusiveBox, the value borrowed through it stays alive until it is dropped
+pub struct BoxGuard<'a, T: 'a> {
+    cell: &'a ExclusiveBox<T>,
+    _pin: GracePin<'a, Box<T>>,
+}
+
+/// A link on an ExclusiveBox, which keeps the cell pinned
+pub struct LinkedBox<'a, T: 'a> {
+    link: LinkedPtr<'a, T>,
+    guard: BoxGuard<'a, T>,
+}
+
+/// A failed store_conditional on an ExclusiveBox
+///
+/// This hands back the box which was not stored, along with a new link
+pub struct BoxFailure<'a, T: 'a> {
+    val: Option<Box<T>>,
+    link: LinkedBox<'a, T>,
+    reason: FailureReason,
+}
+
+#[inline(always)]
+fn into_raw<T>(val: Option<Box<T>>) -> *mut T {
+    match val {
+        Some(b) => Box::into_raw(b),
+        None => ptr::null_mut(),
+    }
+}
+
+#[inline(always)]
+unsafe fn from_raw<T>(ptr: *mut T) -> Option<Box<T>> {
+    match ptr.is_null() {
+        true => None,
+        false => Some(Box::from_raw(ptr)),
+    }
+}
+
+impl<T> ExclusiveBox<T> {
+
+    pub fn new(val: Option<Box<T>>) -> ExclusiveBox<T> {
+        let cell = ExclusiveBox::null();
+        cell.ptr.store_direct(into_raw(val), Relaxed);
+        cell
+    }
+
+    /// Creates an empty cell, usable in statics
+    pub const fn null() -> ExclusiveBox<T> {
+        ExclusiveBox {
+            ptr: ExclusivePtr::NULL,
+            grace: Grace::new(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Pins the cell so that the current value can be borrowed
+    pub fn pin(&self) -> BoxGuard<'_, T> {
+        BoxGuard {
+            cell: self,
+            _pin: self.grace.pin(),
+        }
+    }
+
+    /// Performs an exclusive load on the cell, pinning it for the life of the link
+    pub fn load_linked(&self) -> LinkedBox<'_, T> {
+        let guard = self.pin();
+        LinkedBox {
+            link: self.ptr.load_linked(ordering::Acquire),
+            guard,
+        }
+    }
+
+    /// Stores a new value, dropping the old one once no reader can see it
+    pub fn store(&self, val: Option<Box<T>>) {
+        let old = self.ptr.exchange_direct(into_raw(val), SeqCst);
+        self.retire(old);
+    }
+
+    /// Gives mutable access to the value, since no one else can be reading it
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        unsafe { self.ptr.load(Relaxed).as_mut() }
+    }
+
+    pub fn into_inner(self) -> Option<Box<T>> {
+        let old = self.ptr.exchange_direct(ptr::null_mut(), Relaxed);
+        unsafe { from_raw(old) }
+    }
+
+    fn retire(&self, old: *mut T) {
+        if let Some(old) = unsafe { from_raw(old) } {
+            self.grace.retire(old);
+        }
+    }
+}
+
+impl<T> Drop for ExclusiveBox<T> {
+    fn drop(&mut self) {
+        unsafe { from_raw(self.ptr.load(Relaxed)) };
+    }
+}
+
+impl<T> Default for ExclusiveBox<T> {
+    fn default() -> ExclusiveBox<T> {
+        ExclusiveBox::null()
+    }
+}
+
+unsafe impl<T: Send> Send for ExclusiveBox<T> {}
+unsafe impl<T: Send + Sync> Sync for ExclusiveBox<T> {}
+
+impl<'a, T> BoxGuard<'a, T> {
+
+    /// Borrows the current value of the cell
+    pub fn get(&self) -> Option<&T> {
+        unsafe { self.cell.ptr.load(Acquire).as_ref() }
+    }
+}
+
+impl<'a, T> LinkedBox<'a, T> {
+
+    /// Borrows the linked value
+    pub fn get(&self) -> Option<&T> {
+        unsafe { self.link.get().as_ref() }
+    }
+
+    /// Stores the new value if the cell is unchanged since the link
+    ///
+    /// On success, the old value is dropped once no reader can see it.
+    /// On failure, the error holds the value back along with a new link
+    pub fn store_conditional(self, val: Option<Box<T>>) -> Result<(), BoxFailure<'a, T>> {
+        let LinkedBox { link, guard } = self;
+        let old = link.get();
+        let new = into_raw(val);
+        match link.store_conditional(new, ordering::Release) {
+            Ok(()) => {
+                // Our own pin would only hold the old value back
+                let cell = guard.cell;
+                drop(guard);
+                cell.retire(old);
+                Ok(())
+            },
+            Err(fail) => {
+                let reason = fail.reason();
+                Err(BoxFailure {
+                    val: unsafe { from_raw(new) },
+                    link: LinkedBox {
+                        link: fail.into_link(),
+                        guard,
+                    },
+                    reason,
+                })
+            },
+        }
+    }
+}
+
+impl<'a, T> BoxFailure<'a, T> {
+
+    pub fn reason(&self) -> FailureReason {
+        self.reason
+    }
+
+    /// Returns the value which was not stored and the new link to retry with
+    pub fn into_parts(self) -> (Option<Box<T>>, LinkedBox<'a, T>) {
+        (self.val, self.link)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate crossbeam;
+    use self::crossbeam::scope;
+    use super::*;
+    use test_util::Counted;
+    use std::sync::Barrier;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::SeqCst;
+
+    #[test]
+    fn test_store_drop() {
+        let drops = AtomicUsize::new(0);
+        {
+            let cell = ExclusiveBox::new(Some(Box::new(Counted(1, &drops))));
+            assert_eq!(cell.pin().get().unwrap().0, 1);
+            // Nobody is pinned, so the old value goes right away
+            cell.store(Some(Box::new(Counted(2, &drops))));
+            assert_eq!(drops.load(SeqCst), 1);
+
+            // Storing under a pin of our own doesn't wait for it
+            let guard = cell.pin();
+            let old = guard.get().unwrap();
+            cell.store(Some(Box::new(Counted(3, &drops))));
+            let ll = cell.load_linked();
+            ll.store_conditional(Some(Box::new(Counted(4, &drops)))).ok().unwrap();
+            assert_eq!(old.0, 2);
+            assert_eq!(drops.load(SeqCst), 1);
+            drop(guard);
+
+            cell.store(Some(Box::new(Counted(5, &drops))));
+            assert_eq!(drops.load(SeqCst), 4);
+            assert_eq!(cell.pin().get().unwrap().0, 5);
+        }
+        assert_eq!(drops.load(SeqCst), 5);
+    }
+
+    #[test]
+    fn test_store_conditional() {
+        let cell = ExclusiveBox::new(Some(Box::new(1)));
+        let ll = cell.load_linked();
+        assert_eq!(ll.get(), Some(&1));
+        assert!(ll.store_conditional(Some(Box::new(2))).is_ok());
+
+        let barrier = Barrier::new(2);
+        scope(|scope| {
+            scope.spawn(|| {
+                let ll = cell.load_linked();
+                barrier.wait();
+                while cell.pin().get() != Some(&3) {}
+                let fail = ll.store_conditional(Some(Box::new(4))).unwrap_err();
+                let (val, ll) = fail.into_parts();
+                assert_eq!(val, Some(Box::new(4)));
+                assert_eq!(ll.get(), Some(&3));
+            });
+            barrier.wait();
+            cell.store(Some(Box::new(3)));
+        });
+        assert_eq!(cell.into_inner(), Some(Box::new(3)));
+    }
+
+    #[test]
+    fn test_mt_readers() {
+        let num_run: usize = 10000;
+        let cell = ExclusiveBox::new(Some(Box::new(vec![0usize; 16])));
+
+        scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..num_run {
+                        let guard = cell.pin();
+                        let v = guard.get().unwrap();
+                        assert!(v.iter().all(|x| *x == v[0]));
+                    }
+                });
+            }
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    for i in 0..num_run / 10 {
+                        let mut val = Some(Box::new(vec![i; 16]));
+                        let mut ll = cell.load_linked();
+                        loop {
+                            match ll.store_conditional(val) {
+                                Ok(()) => break,
+                                Err(fail) => {
+                                    let (v, nll) = fail.into_parts();
+                                    val = v;
+                                    ll = nll;
+                                },
+                            }
+                        }
+                    }
+                });
+            }
+        });
+    }
+}
@@ -0,0 +1,296 @@
+//! ExclusivePtr on top of crossbeam's epoch based reclamation
+//!
+//! ExclusiveShared takes the place of mem::epoch::Atomic, with links instead of cas.
+//! Values go in as Owned and come out as references for the life of a Guard,
+//! and replaced values are handed to the guard once no thread can reach them.
+//!
+//! crossbeam 0.2 only frees the memory of unlinked values, it never runs their
+//! destructors. Anything owning heap memory or other resources leaks when it is
+//! unlinked, so this is best kept to plain data. Like Atomic, the cell does not
+//! free its value when dropped either.
+//!
+//! crossbeam can only unlink a Shared, and only hands those out for pointers
+//! held in an Atomic. So each value lives in a node along with an Atomic
+//! pointing back at the node, and that is what gets unlinked.
+
+use std::ptr;
+use std::marker::PhantomData;
+use std::sync::atomic::Ordering;
+use std::sync::atomic::Ordering::Relaxed;
+
+use crossbeam::mem::epoch::{self, Atomic, Guard, Owned};
+
+use ordering;
+use failure::Failure;
+use {ExclusivePtr, LinkedPtr, FailureReason};
+
+// The value comes first, so a reference to it points at the node
+#[repr(C)]
+struct Node<T> {
+    val: T,
+    this: Atomic<Node<T>>,
+}
+
+pub struct ExclusiveShared<T> {
+    ptr: ExclusivePtr<Node<T>>,
+    marker: PhantomData<T>,
+}
+
+/// A link on an ExclusiveShared, valid for the life of the guard
+pub struct LinkedShared<'g, T: 'g> {
+    link: LinkedPtr<'g, Node<T>>,
+    guard: &'g Guard,
+}
+
+/// A failed store_conditional on an ExclusiveShared
+///
+/// This hands back the value which was not stored, along with a new link
+pub struct SharedFailure<'g, T: 'g> {
+    val: Option<Owned<T>>,
+    link: LinkedShared<'g, T>,
+    reason: FailureReason,
+}
+
+// Moves the value into a node which the guard can later unlink
+fn into_node<T>(val: T, guard: &Guard) -> *mut Node<T> {
+    let node = Owned::new(Node {
+        val,
+        this: Atomic::null(),
+    });
+    let node = Atomic::null().store_and_ref(node, Relaxed, guard);
+    node.this.store_shared(Some(node), Relaxed);
+    node.as_raw()
+}
+
+// Takes the value back out of a node which was never stored
+#[inline(always)]
+unsafe fn from_node<T>(node: *mut Node<T>) -> Option<Owned<T>> {
+    match node.is_null() {
+        true => None,
+        false => Some(Owned::new(Box::from_raw(node).val)),
+    }
+}
+
+#[inline(always)]
+unsafe fn as_val<'g, T>(node: *mut Node<T>) -> Option<&'g T> {
+    node.as_ref().map(|n| &n.val)
+}
+
+#[inline(always)]
+fn as_node<T>(val: Option<&T>) -> *mut Node<T> {
+    val.map(|r| r as *const T as *mut Node<T>).unwrap_or(ptr::null_mut())
+}
+
+impl<T> ExclusiveShared<T> {
+
+    pub fn new(val: T) -> ExclusiveShared<T> {
+        let guard = epoch::pin();
+        ExclusiveShared {
+            ptr: ExclusivePtr::new(into_node(val, &guard)),
+            marker: PhantomData,
+        }
+    }
+
+    /// Creates an empty cell, usable in statics
+    pub const fn null() -> ExclusiveShared<T> {
+        ExclusiveShared {
+            ptr: ExclusivePtr::NULL,
+            marker: PhantomData,
+        }
+    }
+
+    /// Loads the value, which stays valid for the life of the guard
+    pub fn load<'g>(&self, ord: Ordering, _: &'g Guard) -> Option<&'g T> {
+        unsafe { as_val(self.ptr.load(ord)) }
+    }
+
+    /// Performs an exclusive load on the cell, with Acquire ordering
+    pub fn load_linked<'g>(&'g self, guard: &'g Guard) -> LinkedShared<'g, T> {
+        LinkedShared {
+            link: self.ptr.load_linked(ordering::Acquire),
+            guard,
+        }
+    }
+
+    /// Hands a replaced value to the guard, like Guard::unlinked
+    ///
+    /// Its memory is freed once no thread can still be reading it,
+    /// but as with everything unlinked in crossbeam 0.2, it isn't dropped.
+    ///
+    /// # Safety
+    ///
+    /// The value must have been read out of an ExclusiveShared,
+    /// and must no longer be reachable from any of them
+    pub unsafe fn unlinked(guard: &Guard, val: &T) {
+        let node = as_node(Some(val));
+        guard.unlinked((*node).this.load(Relaxed, guard).unwrap());
+    }
+}
+
+impl<T> Default for ExclusiveShared<T> {
+    fn default() -> ExclusiveShared<T> {
+        ExclusiveShared::null()
+    }
+}
+
+unsafe impl<T: Send + Sync> Send for ExclusiveShared<T> {}
+unsafe impl<T: Send + Sync> Sync for ExclusiveShared<T> {}
+
+impl<'g, T> LinkedShared<'g, T> {
+
+    pub fn get(&self) -> Option<&'g T> {
+        unsafe { as_val(self.link.get()) }
+    }
+
+    /// Stores the new value if the cell is unchanged since the link, with Release ordering
+    ///
+    /// On success, returns the replaced value. It is still valid for the life
+    /// of the guard, and should be passed to ExclusiveShared::unlinked once
+    /// unreachable. On failure, the error holds the value back along with a new link
+    pub fn store_conditional(self, val: Option<Owned<T>>)
+                             -> Result<Option<&'g T>, SharedFailure<'g, T>> {
+        let old = self.link.get();
+        let new = match val {
+            Some(val) => into_node(val.into_inner(), self.guard),
+            None => ptr::null_mut(),
+        };
+        match self.link.store_conditional(new, ordering::Release) {
+            Ok(()) => Ok(unsafe { as_val(old) }),
+            Err(fail) => {
+                let reason = fail.reason();
+                Err(SharedFailure {
+                    val: unsafe { from_node(new) },
+                    link: LinkedShared {
+                        link: fail.into_link(),
+                        guard: self.guard,
+                    },
+                    reason,
+                })
+            },
+        }
+    }
+
+    /// Like store_conditional, but hands the replaced value straight to the guard
+    ///
+    /// # Safety
+    ///
+    /// The cell must have held the one reference to the replaced value,
+    /// which is the case for most linked structures. Like
+    /// ExclusiveShared::unlinked, this frees the value without dropping it
+    pub unsafe fn store_conditional_unlinked(self, val: Option<Owned<T>>)
+                                             -> Result<(), SharedFailure<'g, T>> {
+        let guard = self.guard;
+        self.store_conditional(val).map(|old| {
+            if let Some(old) = old {
+                ExclusiveShared::unlinked(guard, old);
+            }
+        })
+    }
+
+    /// Stores a value which is already shared, like Atomic::cas_shared
+    ///
+    /// On failure, returns the reason along with the new link
+    ///
+    /// # Safety
+    ///
+    /// The value must have been read out of an ExclusiveShared
+    pub unsafe fn store_shared_conditional(self, val: Option<&'g T>)
+                                           -> Result<Option<&'g T>, Failure<LinkedShared<'g, T>>> {
+        let old = self.link.get();
+        match self.link.store_conditional(as_node(val), ordering::Release) {
+            Ok(()) => Ok(as_val(old)),
+            Err(fail) => {
+                let reason = fail.reason();
+                Err(Failure::new(LinkedShared {
+                    link: fail.into_link(),
+                    guard: self.guard,
+                }, reason))
+            },
+        }
+    }
+}
+
+impl<'g, T> SharedFailure<'g, T> {
+
+    pub fn reason(&self) -> FailureReason {
+        self.reason
+    }
+
+    /// Returns the value which was not stored and the new link to retry with
+    pub fn into_parts(self) -> (Option<Owned<T>>, LinkedShared<'g, T>) {
+        (self.val, self.link)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crossbeam::scope;
+    use crossbeam::mem::epoch::{self, Owned};
+    use super::*;
+    use std::sync::atomic::Ordering::Relaxed;
+
+    #[test]
+    fn test_store_conditional() {
+        let cell = ExclusiveShared::new(1);
+        let guard = epoch::pin();
+        let ll = cell.load_linked(&guard);
+        assert_eq!(*ll.get().unwrap(), 1);
+        let old = ll.store_conditional(Some(Owned::new(2))).ok().unwrap().unwrap();
+        assert_eq!(*old, 1);
+        unsafe { ExclusiveShared::unlinked(&guard, old) };
+
+        let ll = cell.load_linked(&guard);
+        let two = cell.load(Relaxed, &guard);
+        unsafe { cell.load_linked(&guard).store_shared_conditional(None).ok().unwrap() };
+        let (val, ll) = ll.store_conditional(Some(Owned::new(3))).unwrap_err().into_parts();
+        assert_eq!(*val.unwrap(), 3);
+        assert!(ll.get().is_none());
+        assert!(cell.load(Relaxed, &guard).is_none());
+
+        let old = unsafe { cell.load_linked(&guard).store_shared_conditional(two).ok().unwrap() };
+        assert!(old.is_none());
+        assert_eq!(cell.load(Relaxed, &guard), Some(&2));
+        let ll = cell.load_linked(&guard);
+        unsafe {
+            cell.load_linked(&guard).store_shared_conditional(None).ok().unwrap();
+            let fail = ll.store_shared_conditional(two).unwrap_err();
+            assert_eq!(fail.reason(), FailureReason::ValueChanged);
+            assert!(fail.into_link().get().is_none());
+            ExclusiveShared::unlinked(&guard, two.unwrap());
+        }
+    }
+
+    #[test]
+    fn test_mt_unlinked() {
+        let num_run: usize = 10000;
+        let cell = ExclusiveShared::new([0usize; 16]);
+
+        scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for i in 0..num_run {
+                        let guard = epoch::pin();
+                        let v = cell.load(Relaxed, &guard).unwrap();
+                        assert!(v.iter().all(|x| *x == v[0]));
+                        let mut val = Some(Owned::new([i; 16]));
+                        let mut ll = cell.load_linked(&guard);
+                        loop {
+                            match unsafe { ll.store_conditional_unlinked(val) } {
+                                Ok(()) => break,
+                                Err(fail) => {
+                                    let (v, nll) = fail.into_parts();
+                                    val = v;
+                                    ll = nll;
+                                },
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        let guard = epoch::pin();
+        let ll = cell.load_linked(&guard);
+        unsafe { ll.store_conditional_unlinked(None).ok().unwrap() };
+    }
+}
@@ -1,14 +1,17 @@
-/// Like mem::epoch::AtomicPtr, but provides an ll/sc based api on x86, powerpc, arm, aarch64
+//! Like mem::epoch::AtomicPtr, but provides an ll/sc based api on x86, powerpc, arm, aarch64
 
 use std::mem;
 use std::marker::PhantomData;
+use std::cell::UnsafeCell;
 
 use std::sync::atomic::{Ordering, AtomicUsize};
 
+use ordering::{LoadOrdering, StoreOrdering};
+use failure::{Failure, FailureReason};
+
 #[cfg(target_pointer_width = "64")]
 mod multi_size {
-
-    pub const PTR_MOD: usize = 15;
+    use std::arch::asm;
 
     #[inline(always)]
     pub unsafe fn cas_tagged(ptr: *const usize, old: (usize, usize), nval: usize)
@@ -17,23 +20,28 @@ mod multi_size {
         let mut counter: usize = old.1;
         let ncounter: usize = counter.wrapping_add(1);
         let new = nval;
-        let succ: bool;
-        asm!("lock cmpxchg16b ($7)\n\t
-          sete $0\n\t"
-          : "=r" (succ), "={rax}" (val), "={rdx}" (counter)
-          : "1"(val), "2"(counter), "{rbx}"(new), "{rcx}"(ncounter), "r"(ptr)
-          : "memory"
-          : "volatile");
-        // Returned values only matter if succ is false,
-        // in which case thee right ones are loaded into memory
+        // rbx can't be named as an operand, so the new value is swapped into it
+        // around the cas. The pointer is pinned to rsi, since a plain reg operand
+        // may be given rbx itself and would then be overwritten by the swap
+        asm!("xchg {new}, rbx",
+             "lock cmpxchg16b xmmword ptr [rsi]",
+             "mov rbx, {new}",
+             in("rsi") ptr,
+             new = inout(reg) new => _,
+             inout("rax") val,
+             inout("rdx") counter,
+             in("rcx") ncounter,
+             options(nostack));
+        // On failure rax:rdx hold the current pair, which differs from the old one,
+        // and on success they are left untouched
+        let succ = (val, counter) == old;
         (succ, (val, counter))
     }
 }
 
 #[cfg(target_pointer_width = "32")]
 mod multi_size {
-
-    pub const PRT_MOD: usize = 7;
+    use std::arch::asm;
 
     #[inline(always)]
     pub unsafe fn cas_tagged(ptr: *const usize, old: (usize, usize), nval: usize)
@@ -42,15 +50,21 @@ mod multi_size {
         let mut counter: usize = old.1;
         let ncounter: usize = counter.wrapping_add(1);
         let new = nval;
-        let succ: bool;
-        asm!("lock cmpxchg16b ($7)\n\t
-          sete $0\n\t"
-          : "=r" (succ), "={rax}" (val), "={rdx}" (counter)
-          : "1"(val), "2"(counter), "{rbx}"(new), "{rcx}"(ncounter), "r"(ptr)
-          : "memory"
-          : "volatile");
-        // Returned values only matter if succ is false,
-        // in which case thee right ones are loaded into memory
+        // ebx can't be named as an operand, so the new value is swapped into it
+        // around the cas. The pointer is pinned to edi, since a plain reg operand
+        // may be given ebx itself and would then be overwritten by the swap
+        asm!("xchg {new}, ebx",
+             "lock cmpxchg8b qword ptr [edi]",
+             "mov ebx, {new}",
+             in("edi") ptr,
+             new = inout(reg) new => _,
+             inout("eax") val,
+             inout("edx") counter,
+             in("ecx") ncounter,
+             options(nostack));
+        // On failure eax:edx hold the current pair, which differs from the old one,
+        // and on success they are left untouched
+        let succ = (val, counter) == old;
         (succ, (val, counter))
     }
 }
@@ -58,12 +72,19 @@ mod multi_size {
 use self::multi_size::*;
 
 
-#[repr(C)]
-#[derive(Copy, Clone)]
+/// The value word followed by the counter word, aligned to their combined size
+/// as cmpxchg16b (or cmpxchg8b on 32 bit) requires.
+///
+/// This layout is fixed, so cells may be shared through FFI or shared memory
+/// as long as every party updates the pair with a double width cas.
+///
+/// The words live in UnsafeCells since they are written through shared
+/// references, which also keeps a cell in a static out of read-only memory
+#[cfg_attr(target_pointer_width = "64", repr(C, align(16)))]
+#[cfg_attr(target_pointer_width = "32", repr(C, align(8)))]
 struct Llsc {
-    val: usize,
-    counter: usize,
-    extra: usize, //we adjust which is actually the real one due to alignment
+    val: UnsafeCell<usize>,
+    counter: UnsafeCell<usize>,
 }
 
 #[inline(always)]
@@ -85,14 +106,28 @@ unsafe fn exchange_to(ptr: *const usize, n: usize, ord: Ordering) -> usize {
 }
 
 impl Llsc {
-    pub unsafe fn get_ptr(&self) -> *const usize {
-        let addr = (&self.counter as *const usize) as usize;
-        (addr & !PTR_MOD) as *const usize
+    const fn new(val: usize) -> Llsc {
+        Llsc {
+            val: UnsafeCell::new(val),
+            counter: UnsafeCell::new(0),
+        }
     }
 
+    pub fn get_ptr(&self) -> *const usize {
+        self.val.get()
+    }
+
+    pub unsafe fn get_val(&self, ord: Ordering) -> usize {
+        load_from(self.get_ptr(), ord)
+    }
+
+    // The counter is read first, and with Acquire (free on x86), so the value
+    // can never be older than the counter it is paired with. If the pair is
+    // still current when the cas runs, the value was written by the
+    // same store that wrote the counter, which the value load synchronizes with
     pub unsafe fn get_vals(&self, ord: Ordering) -> (usize, usize) {
-        let ptr = self.get_ptr();
-        (load_from(ptr, ord), *ptr.offset(1))
+        let counter = load_from(self.counter.get(), Ordering::Acquire);
+        (load_from(self.get_ptr(), ord), counter)
     }
 
     pub unsafe fn set_val(&self, val: usize, ord: Ordering) {
@@ -104,22 +139,22 @@ impl Llsc {
     }
 }
 
-pub trait Isusize {
+pub trait IsUsize {
     fn from_usize(val: usize) -> Self;
     fn to_usize(&self) -> usize;
 }
 
-impl Isusize for usize {
+impl IsUsize for usize {
     fn from_usize(val: usize) -> usize {
-        val as usize
+        val
     }
 
     fn to_usize(&self) -> usize {
-        *self as usize
+        *self
     }
 }
 
-impl Isusize for isize {
+impl IsUsize for isize {
     fn from_usize(val: usize) -> isize {
         val as isize
     }
@@ -129,7 +164,7 @@ impl Isusize for isize {
     }
 }
 
-impl<T> Isusize for *mut T {
+impl<T> IsUsize for *mut T {
 
     fn from_usize(val: usize) -> *mut T {
         val as *mut T
@@ -140,10 +175,10 @@ impl<T> Isusize for *mut T {
     }
 }
 
-impl Isusize for bool {
+impl IsUsize for bool {
 
     fn from_usize(val: usize) -> bool {
-        val == 0
+        val != 0
     }
 
     fn to_usize(&self) -> usize {
@@ -151,33 +186,76 @@ impl Isusize for bool {
     }
 }
 
-pub struct ExclusiveData<T: Isusize> {
+pub struct ExclusiveData<T: IsUsize> {
     data: Llsc,
     marker: PhantomData<T>,
 }
 
-pub struct LinkedData<'a, T: 'a + Isusize> {
+pub struct LinkedData<'a, T: 'a + IsUsize> {
     data: (usize, usize),
     ptr: *const usize,
     _borrowck: &'a ExclusiveData<T>,
 }
 
-impl<T: Isusize> ExclusiveData<T> {
+// Trait methods can't be called in a const fn,
+// so each kind of cell gets its own constructor
+
+impl ExclusiveData<usize> {
+    pub const fn new(val: usize) -> ExclusiveUsize {
+        ExclusiveData::from_raw(val)
+    }
+}
+
+impl ExclusiveData<isize> {
+    pub const fn new(val: isize) -> ExclusiveIsize {
+        ExclusiveData::from_raw(val as usize)
+    }
+}
+
+impl ExclusiveData<bool> {
+    pub const fn new(val: bool) -> ExclusiveBool {
+        ExclusiveData::from_raw(val as usize)
+    }
+}
+
+impl<T> ExclusiveData<*mut T> {
+    /// A cell holding a null pointer, usable in statics
+    #[allow(clippy::declare_interior_mutable_const)]
+    pub const NULL: ExclusivePtr<T> = ExclusiveData::from_raw(0);
 
-    pub fn new(val: T) -> ExclusiveData<T> {
+    /// Creates a cell holding the pointer
+    ///
+    /// This can't be const since a pointer can't be turned into
+    /// an integer at compile time, use NULL for statics instead
+    pub fn new(val: *mut T) -> ExclusivePtr<T> {
+        ExclusiveData::from_raw(val as usize)
+    }
+}
+
+impl<T: IsUsize> ExclusiveData<T> {
+
+    const fn from_raw(val: usize) -> ExclusiveData<T> {
         ExclusiveData {
-            data: Llsc {
-                val: val.to_usize(),
-                counter: val.to_usize(),
-                extra: 0,
-            },
+            data: Llsc::new(val),
             marker: PhantomData,
         }
     }
 
+    /// Creates a cell holding any kind of value, for generic code
+    pub(crate) fn from_value(val: T) -> ExclusiveData<T> {
+        ExclusiveData::from_raw(val.to_usize())
+    }
+
     /// Loads the value from the pointer with the given ordering
     pub fn load(&self, ord: Ordering) -> T {
-        unsafe { T::from_usize(self.data.get_vals(ord).0) }
+        unsafe { T::from_usize(self.data.get_val(ord)) }
+    }
+
+    /// Loads the value together with the counter, which every successful
+    /// store_conditional bumps
+    pub(crate) fn load_counted(&self, ord: Ordering) -> (T, usize) {
+        let (val, counter) = unsafe { self.data.get_vals(ord) };
+        (T::from_usize(val), counter)
     }
 
     /// Stores directly to the pointer without updating the counter
@@ -204,10 +282,10 @@ impl<T: Isusize> ExclusiveData<T> {
     /// and store_conditional, this will always fail. This is stronger the cas
     /// since cas can succedd when modifications have occured as long as the end
     /// result is the same. However, this will always fail in a scenario where cas would fail.
-    pub fn load_linked(&self, ord: Ordering) -> LinkedData<T> {
+    pub fn load_linked<O: LoadOrdering>(&self, ord: O) -> LinkedData<'_, T> {
         unsafe {
             LinkedData {
-                data: self.data.get_vals(ord),
+                data: self.data.get_vals(ord.load_ordering()),
                 ptr: self.data.get_ptr(),
                 _borrowck: self,
             }
@@ -215,7 +293,7 @@ impl<T: Isusize> ExclusiveData<T> {
     }
 }
 
-impl<'a, T: Isusize> LinkedData<'a, T> {
+impl<'a, T: IsUsize> LinkedData<'a, T> {
 
     pub fn get(&self) -> T {
         T::from_usize(self.data.0)
@@ -227,16 +305,42 @@ impl<'a, T: Isusize> LinkedData<'a, T> {
     /// and store_conditional, this will always fail. This is stronger the cas
     /// since cas can succedd when modifications have occured as long as the end
     /// result is the same. However, this will always fail in a scenario where cas would fail.
-    pub fn store_conditional(self, val: T, _: Ordering) -> Option<LinkedData<'a, T>> {
+    ///
+    /// On failure, the error holds why it failed and a new link with the
+    /// same ordering as this one
+    pub fn store_conditional<O: StoreOrdering>(self, val: T, ord: O)
+                                               -> Result<(), ScFailure<'a, T>> {
+        // the reload is part of the locked cmpxchg16b, so Relaxed is just as strong here
+        self.store_conditional_explicit(val, ord, ::ordering::Relaxed)
+    }
+
+    /// Performs a conditional store on the pointer, with a separate ordering for the
+    /// new link returned on failure, like compare_exchange
+    ///
+    /// lock cmpxchg16b is a full barrier, so every store ordering is free here,
+    /// and the values it reads back on failure already satisfy every failure ordering
+    pub fn store_conditional_explicit<S, F>(self, val: T, success: S, failure: F)
+                                            -> Result<(), ScFailure<'a, T>>
+        where S: StoreOrdering, F: LoadOrdering {
+        // still validates a run time Ordering
+        success.store_ordering();
+        failure.load_ordering();
         unsafe {
             let (succ, res) = cas_tagged(self.ptr, self.data, val.to_usize());
+            // cmpxchg16b never fails spuriously
             match succ {
-                true => None,
-                false => Some(LinkedData {
-                    data: res,
-                    ptr: self.ptr,
-                    _borrowck: self._borrowck,
-                })
+                true => Ok(()),
+                false => {
+                    let reason = match res.0 == self.data.0 {
+                        true => FailureReason::VersionChanged,
+                        false => FailureReason::ValueChanged,
+                    };
+                    Err(Failure::new(LinkedData {
+                        data: res,
+                        ptr: self.ptr,
+                        _borrowck: self._borrowck,
+                    }, reason))
+                }
             }
         }
     }
@@ -247,15 +351,18 @@ impl<'a, T: Isusize> LinkedData<'a, T> {
     /// and store_conditional, this will always fail. This is stronger the cas
     /// since cas can succedd when modifications have occured as long as the end
     /// result is the same. However, this will always fail in a scenario where cas would fail.
-    pub fn try_store_conditional(self, val: T, _: Ordering) -> bool {
+    ///
+    /// lock cmpxchg16b is a full barrier, so every store ordering is free here
+    pub fn try_store_conditional<O: StoreOrdering>(self, val: T, ord: O) -> bool {
+        ord.store_ordering();
         unsafe {
             cas_tagged(self.ptr, self.data, val.to_usize()).0
         }
     }
 }
 
-unsafe impl<T: Isusize> Send for ExclusiveData<T> {}
-unsafe impl<T: Isusize> Sync for ExclusiveData<T> {}
+unsafe impl<T: IsUsize> Send for ExclusiveData<T> {}
+unsafe impl<T: IsUsize> Sync for ExclusiveData<T> {}
 
 pub type ExclusivePtr<T> = ExclusiveData<*mut T>;
 pub type ExclusiveUsize = ExclusiveData<usize>;
@@ -269,3 +376,5 @@ pub type LinkedPtr<'a, T> = LinkedData<'a, *mut T>;
 pub type LinkedUsize<'a> = LinkedData<'a, usize>;
 pub type LinkedIsize<'a> = LinkedData<'a, isize>;
 pub type LinkedBool<'a> = LinkedData<'a, bool>;
+
+pub type ScFailure<'a, T> = Failure<LinkedData<'a, T>>;
@@ -1,176 +1,154 @@
-/// Like mem::epoch::AtomicPtr, but provides an ll/sc based api on x86, powerpc, arm, aarch64
+//! Like mem::epoch::AtomicPtr, but provides an ll/sc based api on x86, powerpc, arm, aarch64
 
 use std::mem;
 use std::marker::PhantomData;
+use std::cell::UnsafeCell;
 
 use std::sync::atomic::{Ordering, AtomicUsize};
-use std::sync::atomic::Ordering::*;
 
+use ordering::{LoadOrdering, StoreOrdering};
+use failure::{Failure, FailureReason};
+
+
+// load_exc and store_exc only ever see orderings that passed
+// LoadOrdering and StoreOrdering, so the invalid arms are unreachable.
+// With the marker types the matches fold away to just the needed fences
+//
+// aarch64 and arm link the value and the counter after it as one pair with
+// the doubleword exclusives. powerpc only has a single word reservation,
+// so there the counter is left out and always reads as 0
 
 #[cfg(target_arch = "aarch64")]
 mod multi_arch {
-    #[inline(awlays)]
-    unsafe fn load_exc(ptr: *const usize, ord: Ordering, _: bool) {
-        let rval: usize;
+    use std::arch::asm;
+    use std::sync::atomic::Ordering;
+    use std::sync::atomic::Ordering::*;
+
+    pub const COUNTED: bool = true;
+
+    #[inline(always)]
+    pub unsafe fn load_exc(ptr: *const usize, ord: Ordering) -> (usize, usize) {
+        let val: usize;
+        let counter: usize;
         match ord {
-            Relaxed => {
-                asm!("ldxr %1, [%0]"
-                    : "=r" (rval)
-                    : "r" (ptr)
-                    : "volatile")
-            },
-            Acquire | SeqCst => {
-                asm!("ldaxr %1, [%0]"
-                    : "=r" (rval)
-                    : "r" (ptr)
-                    : "volatile")
-            },
-            Release | AcqRel => panic!("Invalid load ordering"),
+            Relaxed => asm!("ldxp {val}, {counter}, [{ptr}]",
+                            val = out(reg) val, counter = out(reg) counter,
+                            ptr = in(reg) ptr, options(nostack)),
+            Acquire | SeqCst => asm!("ldaxp {val}, {counter}, [{ptr}]",
+                                     val = out(reg) val, counter = out(reg) counter,
+                                     ptr = in(reg) ptr, options(nostack)),
+            _ => unreachable!(),
         }
-        rval
+        (val, counter)
     }
 
+    /// Returns true if the store succeeded
     #[inline(always)]
-    unsafe fn store_exc(ptr: *const usize, val: usize, ord: Ordering,
-                        rord: Ordering, reload: bool) -> (bool, usize) {
-        let succ: bool;
+    pub unsafe fn store_exc(ptr: *const usize, new: (usize, usize), ord: Ordering) -> bool {
+        let status: u32;
         match ord {
-            Relaxed => {
-                asm!("stxr %0 %1 [%2]"
-                   : "=r" (succ)
-                   : "r" (val), "r" (ptr)
-                   : "memory"
-                   : "volatile")
-            },
-            Release | SeqCst => {
-                asm!("stlxr %0 %1 [%2]"
-                   : "=r" (succ)
-                   : "r" (val), "r" (ptr)
-                   : "memory"
-                   : "volatile")
-            },
-            Acquire | AcqRel => panic("Invalid Store Ordering"),
-        }
-        if succ {
-            (true, mem::uninitialized() )
-        }
-        else {
-            (false, if reload { load_exc(ptr, rord, false) }
-                    else { mem::uninitialized() })
+            Relaxed => asm!("stxp {status:w}, {val}, {counter}, [{ptr}]",
+                            status = out(reg) status, val = in(reg) new.0,
+                            counter = in(reg) new.1, ptr = in(reg) ptr,
+                            options(nostack)),
+            Release | SeqCst => asm!("stlxp {status:w}, {val}, {counter}, [{ptr}]",
+                                     status = out(reg) status, val = in(reg) new.0,
+                                     counter = in(reg) new.1, ptr = in(reg) ptr,
+                                     options(nostack)),
+            _ => unreachable!(),
         }
+        status == 0
     }
 }
 
 #[cfg(target_arch = "arm")]
 mod multi_arch {
+    use std::arch::asm;
+    use std::sync::atomic::Ordering;
+    use std::sync::atomic::Ordering::*;
 
-    // This may be able to eliminate a dmb sy in the mismatched seqcst case?
+    pub const COUNTED: bool = true;
 
-    #[inline(awlays)]
-    unsafe fn load_exc(ptr: *const usize, ord: Ordering, rseqcst: bool) {
-        let rval: usize;
-        // This flag allows more efficient ll/sc loops when the ll/sc
-        // flag reloads!
-        if rseqcst && ord == SeqCst { asm!("dmb sy":::"memory":"volatile") }
-        asm!("ldxr %1, [%0]"
-             : "=r" (rval)
-             : "r" (ptr)
-             : "volatile");
+    // In arm mode the pair has to be an even register and the one after it
+    #[inline(always)]
+    pub unsafe fn load_exc(ptr: *const usize, ord: Ordering) -> (usize, usize) {
+        let val: usize;
+        let counter: usize;
+        asm!("ldrexd r0, r1, [{ptr}]",
+             ptr = in(reg) ptr, out("r0") val, out("r1") counter,
+             options(nostack));
         match ord {
             Relaxed => (),
-            Acquire | SeqCst => asm!("dmb sy":::"memory":"volatile"),
-            Release | AcqRel => panic!("Invalid load ordering"),
+            Acquire | SeqCst => asm!("dmb ish", options(nostack)),
+            _ => unreachable!(),
         }
-        rval
+        (val, counter)
     }
 
+    /// Returns true if the store succeeded
     #[inline(always)]
-    unsafe fn store_exc(ptr: *const usize, val: usize, ord: Ordering,
-                        rord: Ordering, reload: bool) -> (bool, usize) {
-        let succ: bool;
-
+    pub unsafe fn store_exc(ptr: *const usize, new: (usize, usize), ord: Ordering) -> bool {
+        let status: usize;
         match ord {
             Relaxed => (),
-            Release | SeqCst => asm!("dmb sy":::"memory":"volatile"),
-            Acquire | AcqRel => panic("Invalid Store Ordering"),
-        }
-        asm!("strex %0 %1 [%2]"
-             : "=r" (succ)
-             : "r" (val), "r" (ptr)
-             : "memory"
-             : "volatile");
-        if ord == SeqCst { asm!("dmb sy":::"memory":"volatile") }
-        if succ {
-            (true, mem::uninitialized() )
-        }
-        else {
-            (false, if reload { load_exc(ptr, rord, ord != SeqCst) }
-                    else { mem::uninitialized() })
+            Release | SeqCst => asm!("dmb ish", options(nostack)),
+            _ => unreachable!(),
         }
+        asm!("strexd {status}, r2, r3, [{ptr}]",
+             status = out(reg) status, ptr = in(reg) ptr,
+             in("r2") new.0, in("r3") new.1, options(nostack));
+        if ord == SeqCst { asm!("dmb ish", options(nostack)) }
+        status == 0
     }
 }
 
 #[cfg(target_arch = "powerpc")]
 mod multi_arch {
+    use std::arch::asm;
+    use std::sync::atomic::Ordering;
+    use std::sync::atomic::Ordering::*;
 
+    pub const COUNTED: bool = false;
 
-    #[inline(awlays)]
-    unsafe fn load_exc(ptr: *const usize, ord: Ordering, _: bool) {
+    #[inline(always)]
+    pub unsafe fn load_exc(ptr: *const usize, ord: Ordering) -> (usize, usize) {
         let rval: usize;
-        // This flag allows more efficient ll/sc loops when the ll/sc
-        // flag reloads!
-        if ord == SeqCst { asm!("sync":::"memory":"volatile") }
-
+        if ord == SeqCst { asm!("sync", options(nostack)) }
+        asm!("lwarx {rval}, 0, {ptr}",
+             rval = out(reg) rval, ptr = in(reg_nonzero) ptr,
+             options(nostack));
         match ord {
-            Relaxed => asm!("lwarx $0, 0, $1"
-                            : "=r" (rval)
-                            : "r" (ptr)
-                            : "volatile"),
-            Acquire | SeqCst => asm!("lwarx $0, 0, $1
-                                      cmpw $1, $1
-                                      bne- $+4
-                                      isync"
-                                      : "=r" (rval)
-                                      : "r" (ptr)
-                                      : "memory"
-                                      : "volatile"),
-            Release | AcqRel => panic!("Invalid load ordering"),
+            Relaxed => (),
+            Acquire | SeqCst => asm!("lwsync", options(nostack)),
+            _ => unreachable!(),
         }
-        rval
+        (rval, 0)
     }
 
+    /// Returns true if the store succeeded
     #[inline(always)]
-    unsafe fn store_exc(ptr: *const usize, val: usize, ord: Ordering,
-                        rord: Ordering, reload: bool) -> (bool, usize) {
+    pub unsafe fn store_exc(ptr: *const usize, new: (usize, usize), ord: Ordering) -> bool {
         let succ: usize;
-
         match ord {
             Relaxed => (),
-            Release => asm!("lwsync":::"memory":"volatile"),
-            SeqCst => asm!("sync":::"memory":"volatile"),
-            Acquire | AcqRel => panic("Invalid Store Ordering"),
-        }
-
-        // The docs for powerpc condition register
-        // and stwcx are absolute nonsense...
-        // just copying from gcc and hoping that it works...
-        asm!("stwcx. %1, 0, %2
-              mfcr %0
-              rlwinm %0, %0, 3, 1"
-             : "=r" (succ)
-             : "r" (val), "r" (ptr)
-             : "memory"
-             : "volatile");
-        if succ == 0 {
-            (true, mem::uninitialized() )
-        }
-        else {
-            (false, if reload { load_exc(ptr, rord, false) }
-                    else { mem::uninitialized() })
+            Release => asm!("lwsync", options(nostack)),
+            SeqCst => asm!("sync", options(nostack)),
+            _ => unreachable!(),
         }
+        // stwcx. sets the EQ bit of cr0 on success,
+        // which is rotated down into the low bit here
+        asm!("stwcx. {val}, 0, {ptr}",
+             "mfcr {succ}",
+             "rlwinm {succ}, {succ}, 3, 31, 31",
+             succ = out(reg) succ, val = in(reg) new.0,
+             ptr = in(reg_nonzero) ptr, out("cr0") _,
+             options(nostack));
+        succ == 1
     }
 }
 
+use self::multi_arch::*;
+
 #[inline(always)]
 unsafe fn load_from(ptr: *const usize, ord: Ordering) -> usize {
     let ptr: *const AtomicUsize = mem::transmute(ptr);
@@ -180,37 +158,48 @@ unsafe fn load_from(ptr: *const usize, ord: Ordering) -> usize {
 #[inline(always)]
 unsafe fn store_to(ptr: *const usize, n: usize, ord: Ordering) {
     let ptr: *const AtomicUsize = mem::transmute(ptr);
-    (&*ptr).store(n as usize, ord)
+    (&*ptr).store(n, ord)
 }
 
 #[inline(always)]
 unsafe fn exchange_to(ptr: *const usize, n: usize, ord: Ordering) -> usize {
     let ptr: *const AtomicUsize = mem::transmute(ptr);
-    (&*ptr).swap(n as usize, ord) as usize
+    (&*ptr).swap(n, ord) as usize
+}
+
+// The strongest failure ordering compare_exchange allows for the given one
+fn failure_ordering(ord: Ordering) -> Ordering {
+    match ord {
+        Ordering::Release => Ordering::Relaxed,
+        Ordering::AcqRel => Ordering::Acquire,
+        ord => ord,
+    }
 }
 
 #[inline(always)]
 unsafe fn cas_to(ptr: *const usize, o: usize, n: usize, ord: Ordering) -> usize {
     let ptr: *const AtomicUsize = mem::transmute(ptr);
-    (&*ptr).compare_and_swap(o as usize, n as usize, ord) as usize
+    match (&*ptr).compare_exchange(o, n, ord, failure_ordering(ord)) {
+        Ok(val) | Err(val) => val,
+    }
 }
 
-pub trait Isusize {
+pub trait IsUsize {
     fn from_usize(val: usize) -> Self;
     fn to_usize(&self) -> usize;
 }
 
-impl Isusize for usize {
+impl IsUsize for usize {
     fn from_usize(val: usize) -> usize {
-        val as usize
+        val
     }
 
     fn to_usize(&self) -> usize {
-        *self as usize
+        *self
     }
 }
 
-impl Isusize for isize {
+impl IsUsize for isize {
     fn from_usize(val: usize) -> isize {
         val as isize
     }
@@ -220,7 +209,7 @@ impl Isusize for isize {
     }
 }
 
-impl<T> Isusize for *mut T {
+impl<T> IsUsize for *mut T {
 
     fn from_usize(val: usize) -> *mut T {
         val as *mut T
@@ -231,10 +220,10 @@ impl<T> Isusize for *mut T {
     }
 }
 
-impl Isusize for bool {
+impl IsUsize for bool {
 
     fn from_usize(val: usize) -> bool {
-        val == 0
+        val != 0
     }
 
     fn to_usize(&self) -> usize {
@@ -242,30 +231,109 @@ impl Isusize for bool {
     }
 }
 
-pub struct ExclusiveData<T: Isusize> {
-    data: usize,
+// UnsafeCells since the words are written through shared references,
+// which also keeps a cell in a static out of read-only memory.
+// The pair is aligned to its size, as the doubleword exclusives require
+#[cfg_attr(target_arch = "aarch64", repr(C, align(16)))]
+#[cfg_attr(target_arch = "arm", repr(C, align(8)))]
+pub struct ExclusiveData<T: IsUsize> {
+    data: UnsafeCell<usize>,
+    #[cfg(not(target_arch = "powerpc"))]
+    counter: UnsafeCell<usize>,
     marker: PhantomData<T>,
 }
 
-pub struct LinkedData<'a, T: 'a + Isusize> {
-    data: usize,
+pub struct LinkedData<'a, T: 'a + IsUsize> {
+    data: (usize, usize),
     ptr: *const usize,
     ord: Ordering,
-    marker: PhantomData<'a, T>,
+    marker: PhantomData<&'a ExclusiveData<T>>,
+}
+
+// Trait methods can't be called in a const fn,
+// so each kind of cell gets its own constructor
+
+impl ExclusiveData<usize> {
+    pub const fn new(val: usize) -> ExclusiveUsize {
+        ExclusiveData::from_raw(val)
+    }
+}
+
+impl ExclusiveData<isize> {
+    pub const fn new(val: isize) -> ExclusiveIsize {
+        ExclusiveData::from_raw(val as usize)
+    }
 }
 
-impl<T: Isusize> ExclusiveData<T> {
+impl ExclusiveData<bool> {
+    pub const fn new(val: bool) -> ExclusiveBool {
+        ExclusiveData::from_raw(val as usize)
+    }
+}
 
-    pub fn new(val: T) -> ExclusiveData<T> {
+impl<T> ExclusiveData<*mut T> {
+    /// A cell holding a null pointer, usable in statics
+    #[allow(clippy::declare_interior_mutable_const)]
+    pub const NULL: ExclusivePtr<T> = ExclusiveData::from_raw(0);
+
+    /// Creates a cell holding the pointer
+    ///
+    /// This can't be const since a pointer can't be turned into
+    /// an integer at compile time, use NULL for statics instead
+    pub fn new(val: *mut T) -> ExclusivePtr<T> {
+        ExclusiveData::from_raw(val as usize)
+    }
+}
+
+impl<T: IsUsize> ExclusiveData<T> {
+
+    const fn from_raw(val: usize) -> ExclusiveData<T> {
         ExclusiveData {
-            data: val.to_usize(),
+            data: UnsafeCell::new(val),
+            #[cfg(not(target_arch = "powerpc"))]
+            counter: UnsafeCell::new(0),
             marker: PhantomData,
         }
     }
 
+    /// Creates a cell holding any kind of value, for generic code
+    pub(crate) fn from_value(val: T) -> ExclusiveData<T> {
+        ExclusiveData::from_raw(val.to_usize())
+    }
+
     /// Loads the value from the pointer with the given ordering
     pub fn load(&self, ord: Ordering) -> T {
-        unsafe { T::from_usize(load_from(&self.data, ord)) }
+        unsafe { T::from_usize(load_from(self.data.get(), ord)) }
+    }
+
+    /// Loads the value together with the counter, which every successful
+    /// store_conditional bumps
+    ///
+    /// A store_conditional writes both words at once, so when the counter reads
+    /// the same on both sides of the value, the value is the one stored with it
+    #[cfg(not(target_arch = "powerpc"))]
+    pub(crate) fn load_counted(&self, ord: Ordering) -> (T, usize) {
+        // The value load has to be at least Acquire to stay before the recheck
+        let vord = match ord {
+            Ordering::SeqCst => Ordering::SeqCst,
+            _ => Ordering::Acquire,
+        };
+        unsafe {
+            loop {
+                let counter = load_from(self.counter.get(), Ordering::Acquire);
+                let val = load_from(self.data.get(), vord);
+                if load_from(self.counter.get(), Ordering::Relaxed) == counter {
+                    return (T::from_usize(val), counter);
+                }
+            }
+        }
+    }
+
+    /// Loads the value together with the counter, which is always 0 here
+    /// since a single word reservation leaves no room for one
+    #[cfg(target_arch = "powerpc")]
+    pub(crate) fn load_counted(&self, ord: Ordering) -> (T, usize) {
+        (self.load(ord), 0)
     }
 
     /// Stores directly to the pointer without updating the counter
@@ -274,7 +342,7 @@ impl<T: Isusize> ExclusiveData<T> {
     /// But is useful when only used to store to say a null value.
     /// Be careful when using, this must always cause a store_conditional to fail
     pub fn store_direct(&self, val: T, ord: Ordering) {
-        unsafe { store_to(&self.data, val.to_usize(), ord) };
+        unsafe { store_to(self.data.get(), val.to_usize(), ord) };
     }
 
     /// Swaps directly to the pointer without updating the counter
@@ -283,7 +351,13 @@ impl<T: Isusize> ExclusiveData<T> {
     /// But is useful when only used to store to say a null value.
     /// Be careful when using, this must always cause a store_conditional to fail
     pub fn swap_direct(&self, val: T, ord: Ordering) -> T {
-        unsafe { T::from_usize(exchange_to(&self.data, val.to_usize(), ord)) }
+        unsafe { T::from_usize(exchange_to(self.data.get(), val.to_usize(), ord)) }
+    }
+
+    /// Swaps directly to the pointer without updating the counter,
+    /// under the name the other backends use
+    pub fn exchange_direct(&self, val: T, ord: Ordering) -> T {
+        self.swap_direct(val, ord)
     }
 
     /// Cas's directly to the pointer without updating the counter
@@ -292,7 +366,7 @@ impl<T: Isusize> ExclusiveData<T> {
     /// But is useful when only used to store to say a null value.
     /// Be careful when using, this must always cause a store_conditional to fail
     pub fn cas_direct(&self, old: T, val: T, ord: Ordering) -> T {
-        unsafe { T::from_usize(cas_to(&self.data, old.to_usize(),
+        unsafe { T::from_usize(cas_to(self.data.get(), old.to_usize(),
                                     val.to_usize(), ord)) }
     }
 
@@ -302,22 +376,23 @@ impl<T: Isusize> ExclusiveData<T> {
     /// and store_conditional, this will always fail. This is stronger the cas
     /// since cas can succedd when modifications have occured as long as the end
     /// result is the same. However, this will always fail in a scenario where cas would fail.
-    pub fn load_linked(&self, ord: Ordering) -> LinkedData<T> {
+    pub fn load_linked<O: LoadOrdering>(&self, ord: O) -> LinkedData<'_, T> {
+        let ord = ord.load_ordering();
         unsafe {
             LinkedData {
-                data: load_from(&self.data, ord),
-                ptr: &self.data,
-                ord: ord,
+                data: load_exc(self.data.get(), ord),
+                ptr: self.data.get(),
+                ord,
                 marker: PhantomData,
             }
         }
     }
 }
 
-impl<'a, T: Isusize> LinkedData<'a, T> {
+impl<'a, T: IsUsize> LinkedData<'a, T> {
 
     pub fn get(&self) -> T {
-        T::from_usize(self.data)
+        T::from_usize(self.data.0)
     }
 
     /// Performs a conditional store on the pointer, conditional on no modifications occurring
@@ -326,17 +401,44 @@ impl<'a, T: Isusize> LinkedData<'a, T> {
     /// and store_conditional, this will always fail. This is stronger the cas
     /// since cas can succedd when modifications have occured as long as the end
     /// result is the same. However, this will always fail in a scenario where cas would fail.
-    pub fn store_conditional(self, val: T, ord: Ordering) -> Option<LinkedData<'a, T>> {
+    ///
+    /// On failure, the error holds why it failed and a new link with the
+    /// same ordering as this one
+    pub fn store_conditional<O: StoreOrdering>(self, val: T, ord: O)
+                                               -> Result<(), ScFailure<'a, T>> {
+        let rord = self.ord;
+        self.store_conditional_explicit(val, ord, rord)
+    }
+
+    /// Performs a conditional store on the pointer, with a separate ordering for the
+    /// new link returned on failure, like compare_exchange
+    ///
+    /// The new link is a fresh exclusive load, so only the failure ordering applies to it
+    pub fn store_conditional_explicit<S, F>(self, val: T, success: S, failure: F)
+                                            -> Result<(), ScFailure<'a, T>>
+        where S: StoreOrdering, F: LoadOrdering {
+        let rord = failure.load_ordering();
         unsafe {
-            let (succ, res) = store_exc(self.ptr, val.to_usize(), ord,
-                                        self.ord, true);
-            match succ {
-                true => None,
-                false => Some(LinkedData {
-                    data: res,
-                    ptr: self.ptr,
-                    marker: PhantomData,
-                })
+            let new = (val.to_usize(), self.data.1.wrapping_add(1));
+            match store_exc(self.ptr, new, success.store_ordering()) {
+                true => Ok(()),
+                false => {
+                    let data = load_exc(self.ptr, rord);
+                    // An unchanged pair means nothing stored to the cell, unless
+                    // there is no counter, where an ABA looks the same
+                    let reason = match (data.0 == self.data.0, data.1 == self.data.1) {
+                        (false, _) => FailureReason::ValueChanged,
+                        (true, false) => FailureReason::VersionChanged,
+                        (true, true) if COUNTED => FailureReason::Spurious,
+                        (true, true) => FailureReason::ReservationLost,
+                    };
+                    Err(Failure::new(LinkedData {
+                        data,
+                        ptr: self.ptr,
+                        ord: rord,
+                        marker: PhantomData,
+                    }, reason))
+                }
             }
         }
     }
@@ -347,13 +449,14 @@ impl<'a, T: Isusize> LinkedData<'a, T> {
     /// and store_conditional, this will always fail. This is stronger the cas
     /// since cas can succedd when modifications have occured as long as the end
     /// result is the same. However, this will always fail in a scenario where cas would fail.
-    pub fn try_store_conditional(self, val: T, _: Ordering) -> bool {
-        unsafe { store_exc(self.ptr, val.to_usize(), ord, self.ord, false).0 }
+    pub fn try_store_conditional<O: StoreOrdering>(self, val: T, ord: O) -> bool {
+        let new = (val.to_usize(), self.data.1.wrapping_add(1));
+        unsafe { store_exc(self.ptr, new, ord.store_ordering()) }
     }
 }
 
-unsafe impl<T: Isusize> Send for ExclusiveData<T> {}
-unsafe impl<T: Isusize> Sync for ExclusiveData<T> {}
+unsafe impl<T: IsUsize> Send for ExclusiveData<T> {}
+unsafe impl<T: IsUsize> Sync for ExclusiveData<T> {}
 
 pub type ExclusivePtr<T> = ExclusiveData<*mut T>;
 pub type ExclusiveUsize = ExclusiveData<usize>;
@@ -367,3 +470,5 @@ pub type LinkedPtr<'a, T> = LinkedData<'a, *mut T>;
 pub type LinkedUsize<'a> = LinkedData<'a, usize>;
 pub type LinkedIsize<'a> = LinkedData<'a, isize>;
 pub type LinkedBool<'a> = LinkedData<'a, bool>;
+
+pub type ScFailure<'a, T> = Failure<LinkedData<'a, T>>;
@@ -0,0 +1,75 @@
+//! The error returned by a failed store_conditional
+
+use std::fmt;
+
+/// Why a store_conditional failed
+///
+/// Which reasons can come up depends on the backend:
+///
+/// - x86 and x86_64 (cmpxchg over the value and a counter) and the lock based
+///   generic backend: ValueChanged and VersionChanged, since their stores
+///   never fail without a conflict
+/// - aarch64 and arm (doubleword exclusives over the value and a counter):
+///   ValueChanged, VersionChanged and Spurious
+/// - powerpc (a single word reservation, with no counter):
+///   ValueChanged and ReservationLost
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FailureReason {
+    /// The value is different from the one that was linked
+    ValueChanged,
+
+    /// The value is the same, but it has been stored to since it was linked.
+    /// This is an ABA which a plain cas would have missed
+    VersionChanged,
+
+    /// The reservation was lost although neither the value nor the counter
+    /// changed, so nothing conflicted. This is an interrupt, an eviction or a
+    /// store elsewhere in the same granule, and a retry will likely succeed
+    Spurious,
+
+    /// The reservation was lost with the value unchanged, on a backend without
+    /// a counter to tell why. This covers both a store of the same value (an ABA)
+    /// and a spurious loss
+    ReservationLost,
+}
+
+/// A failed store_conditional, holding the reason and a freshly loaded link
+///
+/// Each backend names this as ScFailure<'a, T> for its own LinkedData
+pub struct Failure<L> {
+    link: L,
+    reason: FailureReason,
+}
+
+impl<L> Failure<L> {
+
+    pub(crate) fn new(link: L, reason: FailureReason) -> Failure<L> {
+        Failure {
+            link,
+            reason,
+        }
+    }
+
+    pub fn reason(&self) -> FailureReason {
+        self.reason
+    }
+
+    /// Returns the link loaded when the store failed, to retry with
+    pub fn into_link(self) -> L {
+        self.link
+    }
+
+    /// Returns true if the cell still holds the linked value.
+    ///
+    /// This does not mean nothing wrote to the cell, only that a retry
+    /// will see the same value again
+    pub fn value_unchanged(&self) -> bool {
+        self.reason != FailureReason::ValueChanged
+    }
+}
+
+impl<L> fmt::Debug for Failure<L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ScFailure {{ reason: {:?} }}", self.reason)
+    }
+}
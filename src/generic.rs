@@ -1,48 +1,83 @@
-/// Like mem::epoch::AtomicPtr, but provides an ll/sc based api
+//! Like mem::epoch::AtomicPtr, but provides an ll/sc based api
 
-use std::mem;
 use std::marker::PhantomData;
-use std::sync::atomic::{Ordering, AtomicUsize};
-use std::sync::atomic::Ordering::Relaxed;
-use std::sync::Mutex;
+use std::thread;
+use std::sync::atomic::{Ordering, AtomicUsize, AtomicBool};
+use std::sync::atomic::Ordering::{Relaxed, Acquire, Release};
+
+use ordering::{LoadOrdering, StoreOrdering};
+use failure::{Failure, FailureReason};
 
 #[repr(C)]
 struct Llsc {
     val: AtomicUsize,
     counter: AtomicUsize,
-    m: Mutex<()>, // SLOW!!!!
+    locked: AtomicBool, // SLOW!!!!
+}
+
+// A bare spinlock instead of a Mutex, so that cells can be built in a const fn
+struct LockGuard<'a>(&'a AtomicBool);
+
+impl<'a> Drop for LockGuard<'a> {
+    fn drop(&mut self) {
+        self.0.store(false, Release);
+    }
 }
 
 impl Llsc {
 
+    const fn new(val: usize) -> Llsc {
+        Llsc {
+            val: AtomicUsize::new(val),
+            counter: AtomicUsize::new(0),
+            locked: AtomicBool::new(false),
+        }
+    }
+
+    fn lock(&self) -> LockGuard<'_> {
+        while self.locked.compare_exchange_weak(false, true, Acquire, Relaxed).is_err() {
+            while self.locked.load(Relaxed) {
+                thread::yield_now();
+            }
+        }
+        LockGuard(&self.locked)
+    }
+
+    pub fn get_val(&self, ord: Ordering) -> usize {
+        self.val.load(ord)
+    }
+
+    // Taking the lock keeps the value and counter from different stores
     pub fn get_vals(&self, ord: Ordering) -> (usize, usize) {
+        let _guard = self.lock();
         (self.val.load(ord), self.counter.load(Relaxed))
     }
 
-    pub fn set_val(&self, val: usize) {
-        let _guard = self.m.lock().unwrap();
-        self.val.store(val, Relaxed);
+    // The lock orders writers against each other, but plain loads
+    // never take it, so every store still uses the requested ordering
+    pub fn set_val(&self, val: usize, ord: Ordering) {
+        let _guard = self.lock();
+        self.val.store(val, ord);
     }
 
-    pub fn xchg_val(&self, val: usize) -> usize {
-        let _guard = self.m.lock().unwrap();
-        let rval = self.val.load(Relaxed);
-        self.val.store(val, Relaxed);
-        rval
+    pub fn xchg_val(&self, val: usize, ord: Ordering) -> usize {
+        let _guard = self.lock();
+        self.val.swap(val, ord)
     }
 
-    pub fn cas(&self, oval: usize, ctr: usize, nval: usize, _: bool)
-               -> (bool, (usize, usize)) {
-        let _guard = self.m.lock().unwrap();
-        let cval = self.val.load(Relaxed);
+    pub fn cas(&self, oval: usize, ctr: usize, nval: usize,
+               ord: Ordering, rord: Ordering, _: bool)
+               -> Result<(), (usize, usize)> {
+        let _guard = self.lock();
+        let cval = self.val.load(rord);
         let c_counter = self.counter.load(Relaxed);
         if cval == oval && c_counter == ctr {
-            self.val.store(nval, Relaxed);
             self.counter.store(ctr.wrapping_add(1), Relaxed);
-            (true, unsafe { mem::uninitialized() })
+            self.val.store(nval, ord);
+            Ok(())
         }
         else {
-            (false, (cval, c_counter))
+            Err((cval, c_counter))
         }
     }
 }
@@ -54,11 +89,11 @@ pub trait IsUsize {
 
 impl IsUsize for usize {
     fn from_usize(val: usize) -> usize {
-        val as usize
+        val
     }
 
     fn to_usize(&self) -> usize {
-        *self as usize
+        *self
     }
 }
 
@@ -86,7 +121,7 @@ impl<T> IsUsize for *mut T {
 impl IsUsize for bool {
 
     fn from_usize(val: usize) -> bool {
-        val == 0
+        val != 0
     }
 
     fn to_usize(&self) -> usize {
@@ -102,25 +137,69 @@ pub struct ExclusiveData<T: IsUsize> {
 pub struct LinkedData<'a, T: 'a + IsUsize> {
     data: (usize, usize),
     ex_ptr: &'a Llsc,
+    ord: Ordering,
     marker: PhantomData<T>,
 }
 
+// Trait methods can't be called in a const fn,
+// so each kind of cell gets its own constructor
+
+impl ExclusiveData<usize> {
+    pub const fn new(val: usize) -> ExclusiveUsize {
+        ExclusiveData::from_raw(val)
+    }
+}
+
+impl ExclusiveData<isize> {
+    pub const fn new(val: isize) -> ExclusiveIsize {
+        ExclusiveData::from_raw(val as usize)
+    }
+}
+
+impl ExclusiveData<bool> {
+    pub const fn new(val: bool) -> ExclusiveBool {
+        ExclusiveData::from_raw(val as usize)
+    }
+}
+
+impl<T> ExclusiveData<*mut T> {
+    /// A cell holding a null pointer, usable in statics
+    #[allow(clippy::declare_interior_mutable_const)]
+    pub const NULL: ExclusivePtr<T> = ExclusiveData::from_raw(0);
+
+    /// Creates a cell holding the pointer
+    ///
+    /// This can't be const since a pointer can't be turned into
+    /// an integer at compile time, use NULL for statics instead
+    pub fn new(val: *mut T) -> ExclusivePtr<T> {
+        ExclusiveData::from_raw(val as usize)
+    }
+}
+
 impl<T: IsUsize> ExclusiveData<T> {
 
-    pub fn new(val: T) -> ExclusiveData<T> {
+    const fn from_raw(val: usize) -> ExclusiveData<T> {
         ExclusiveData {
-            data: Llsc {
-                val: AtomicUsize::new(val.to_usize()),
-                counter: AtomicUsize::new(val.to_usize()),
-                m: Mutex::new(()),
-            },
+            data: Llsc::new(val),
             marker: PhantomData,
         }
     }
 
+    /// Creates a cell holding any kind of value, for generic code
+    pub(crate) fn from_value(val: T) -> ExclusiveData<T> {
+        ExclusiveData::from_raw(val.to_usize())
+    }
+
     /// Loads the value from the pointer with the given ordering
     pub fn load(&self, ord: Ordering) -> T {
-        T::from_usize(self.data.get_vals(ord).0)
+        T::from_usize(self.data.get_val(ord))
+    }
+
+    /// Loads the value together with the counter, which every successful
+    /// store_conditional bumps
+    pub(crate) fn load_counted(&self, ord: Ordering) -> (T, usize) {
+        let (val, counter) = self.data.get_vals(ord);
+        (T::from_usize(val), counter)
     }
 
     /// Stores directly to the pointer without updating the counter
@@ -128,8 +207,8 @@ impl<T: IsUsize> ExclusiveData<T> {
     /// This function can still leave one vulnerable to the ABA problem,
     /// But is useful when only used to store to say a null value.
     /// Be careful when using, this must always cause a store_conditional to fail
-    pub fn store_direct(&self, val: T, _: Ordering) {
-        self.data.set_val(val.to_usize())
+    pub fn store_direct(&self, val: T, ord: Ordering) {
+        self.data.set_val(val.to_usize(), ord)
     }
 
     /// Stores directly to the pointer without updating the counter
@@ -137,8 +216,8 @@ impl<T: IsUsize> ExclusiveData<T> {
     /// This function can still leave one vulnerable to the ABA problem,
     /// But is useful when only used to store to say a null value.
     /// Be careful when using, this must always cause a store_conditional to fail
-    pub fn exchange_direct(&self, val: T, _: Ordering) -> T {
-        T::from_usize(self.data.xchg_val(val.to_usize()))
+    pub fn exchange_direct(&self, val: T, ord: Ordering) -> T {
+        T::from_usize(self.data.xchg_val(val.to_usize(), ord))
     }
 
     /// Performs an exclusive load on the pointer
@@ -147,10 +226,12 @@ impl<T: IsUsize> ExclusiveData<T> {
     /// and store_conditional, this will always fail. This is stronger the cas
     /// since cas can succedd when modifications have occured as long as the end
     /// result is the same. However, this will always fail in a scenario where cas would fail.
-    pub fn load_linked(&self, ord: Ordering) -> LinkedData<T> {
+    pub fn load_linked<O: LoadOrdering>(&self, ord: O) -> LinkedData<'_, T> {
+        let ord = ord.load_ordering();
         LinkedData {
             data: self.data.get_vals(ord),
             ex_ptr: &self.data,
+            ord,
             marker: PhantomData,
         }
     }
@@ -168,18 +249,41 @@ impl<'a, T: IsUsize> LinkedData<'a, T> {
     /// and store_conditional, this will always fail. This is stronger the cas
     /// since cas can succedd when modifications have occured as long as the end
     /// result is the same. However, this will always fail in a scenario where cas would fail.
-    pub fn store_conditional(self, val: T, _: Ordering) -> Option<LinkedData<'a, T>> {
-        let (succ, res) = self.ex_ptr.cas(self.data.0,
-                                          self.data.1,
-                                          val.to_usize(),
-                                          true);
-        match succ {
-            true => None,
-            false => Some(LinkedData {
-                data: res,
-                ex_ptr: self.ex_ptr,
-                marker: PhantomData
-            })
+    ///
+    /// On failure, the error holds why it failed and a new link with the
+    /// same ordering as this one
+    pub fn store_conditional<O: StoreOrdering>(self, val: T, ord: O)
+                                               -> Result<(), ScFailure<'a, T>> {
+        let rord = self.ord;
+        self.store_conditional_explicit(val, ord, rord)
+    }
+
+    /// Performs a conditional store on the pointer, with a separate ordering for the
+    /// new link returned on failure, like compare_exchange
+    pub fn store_conditional_explicit<S, F>(self, val: T, success: S, failure: F)
+                                            -> Result<(), ScFailure<'a, T>>
+        where S: StoreOrdering, F: LoadOrdering {
+        let rord = failure.load_ordering();
+        let res = self.ex_ptr.cas(self.data.0,
+                                  self.data.1,
+                                  val.to_usize(),
+                                  success.store_ordering(),
+                                  rord,
+                                  true);
+        match res {
+            Ok(()) => Ok(()),
+            Err(res) => {
+                let reason = match res.0 == self.data.0 {
+                    true => FailureReason::VersionChanged,
+                    false => FailureReason::ValueChanged,
+                };
+                Err(Failure::new(LinkedData {
+                    data: res,
+                    ex_ptr: self.ex_ptr,
+                    ord: rord,
+                    marker: PhantomData
+                }, reason))
+            }
         }
     }
 
@@ -189,8 +293,9 @@ impl<'a, T: IsUsize> LinkedData<'a, T> {
     /// and store_conditional, this will always fail. This is stronger the cas
     /// since cas can succedd when modifications have occured as long as the end
     /// result is the same. However, this will always fail in a scenario where cas would fail.
-    pub fn try_store_conditional(self, val: T, _: Ordering) -> bool {
-        self.ex_ptr.cas(self.data.0, self.data.1, val.to_usize(), false).0
+    pub fn try_store_conditional<O: StoreOrdering>(self, val: T, ord: O) -> bool {
+        self.ex_ptr.cas(self.data.0, self.data.1, val.to_usize(),
+                        ord.store_ordering(), Relaxed, false).is_ok()
     }
 }
 
@@ -210,6 +315,8 @@ pub type LinkedUsize<'a> = LinkedData<'a, usize>;
 pub type LinkedIsize<'a> = LinkedData<'a, isize>;
 pub type LinkedBool<'a> = LinkedData<'a, bool>;
 
+pub type ScFailure<'a, T> = Failure<LinkedData<'a, T>>;
+
 #[cfg(test)]
 mod test {
     extern crate crossbeam;
@@ -223,7 +330,7 @@ mod test {
         let eptr = ExclusivePtr::<usize>::new(ptr::null_mut());
         let ll = eptr.load_linked(Relaxed);
         assert_eq!(eptr.load(Relaxed), ptr::null_mut());
-        assert_eq!(ll.try_store_conditional(&mut val, Relaxed), true);
+        assert!(ll.try_store_conditional(&mut val, Relaxed));
         assert_eq!(eptr.load(Relaxed), &mut val as *mut usize);
     }
 
@@ -236,7 +343,7 @@ mod test {
         assert_eq!(eptr.load(Relaxed), ptr::null_mut());
         eptr.store_direct(&mut val2, Relaxed);
         assert_eq!(eptr.load(Relaxed), &mut val2 as *mut usize);
-        assert_eq!(ll.store_conditional(&mut val, Relaxed).is_some(), true);
+        assert!(ll.store_conditional(&mut val, Relaxed).is_err());
         assert_eq!(eptr.load(Relaxed), &mut val2 as *mut usize);
     }
 
@@ -249,7 +356,7 @@ mod test {
         assert_eq!(eptr.load(Relaxed), ptr::null_mut());
         assert_eq!(eptr.exchange_direct(&mut val2, Relaxed), ptr::null_mut());
         assert_eq!(eptr.load(Relaxed), &mut val2 as *mut usize);
-        assert_eq!(ll.store_conditional(&mut val, Relaxed).is_some(), true);
+        assert!(ll.store_conditional(&mut val, Relaxed).is_err());
         assert_eq!(eptr.load(Relaxed), &mut val2 as *mut usize);
     }
 
@@ -267,8 +374,8 @@ mod test {
                         loop {
                             let next = ll.get() + 1;
                             match ll.store_conditional(next, Relaxed) {
-                                None => break,
-                                Some(nll) => ll = nll,
+                                Ok(()) => break,
+                                Err(fail) => ll = fail.into_link(),
                             }
                         }
                     }
@@ -0,0 +1,160 @@
+//! Grace periods for readers of a single structure
+//!
+//! This works like userspace rcu: readers count themselves in one of two
+//! slots picked by the parity of the phase. Writers don't wait for them.
+//! Whatever a writer unlinks is retired tagged with the phase, and the phase
+//! only moves on once the slot it is about to reuse has emptied. So once it
+//! has moved on twice, every reader which could have seen the value has
+//! unpinned, and the value is dropped.
+//!
+//! Collection is tried whenever something is retired, so a value which can't
+//! be dropped yet waits for a later write, or for the Grace to be dropped.
+//! Neither readers nor writers ever wait.
+
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, fence};
+use std::sync::atomic::Ordering::{Relaxed, Release, SeqCst};
+
+use ordering;
+use ExclusivePtr;
+
+pub struct Grace<T> {
+    readers: [AtomicUsize; 2],
+    phase: AtomicUsize,
+    retired: ExclusivePtr<Retired<T>>,
+}
+
+struct Retired<T> {
+    val: T,
+    phase: usize,
+    next: *mut Retired<T>,
+}
+
+pub struct GracePin<'a, T: 'a> {
+    grace: &'a Grace<T>,
+    slot: usize,
+}
+
+impl<T> Grace<T> {
+
+    pub const fn new() -> Grace<T> {
+        Grace {
+            readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            phase: AtomicUsize::new(0),
+            retired: ExclusivePtr::NULL,
+        }
+    }
+
+    /// Registers a reader, which keeps alive anything retired while it is pinned
+    ///
+    /// Acquire loads made under the pin see every store made before a value it
+    /// doesn't keep alive was retired, since registering is a SeqCst
+    /// read-modify-write ordered after that retire
+    pub fn pin(&self) -> GracePin<'_, T> {
+        loop {
+            let phase = self.phase.load(SeqCst);
+            let slot = phase & 1;
+            self.readers[slot].fetch_add(1, SeqCst);
+            // If the phase moved on, the slot may have already
+            // been checked and found empty
+            if self.phase.load(SeqCst) == phase {
+                return GracePin {
+                    grace: self,
+                    slot,
+                };
+            }
+            self.readers[slot].fetch_sub(1, Release);
+        }
+    }
+
+    /// Drops the value once no reader which could have seen it is pinned
+    ///
+    /// The value must already be unreachable for readers which pin from now on
+    pub fn retire(&self, val: T) {
+        // Orders the unlinking store before the phase is read,
+        // so the tag is no older than any reader which saw the value
+        fence(SeqCst);
+        let node = Box::into_raw(Box::new(Retired {
+            val,
+            phase: self.phase.load(SeqCst),
+            next: ptr::null_mut(),
+        }));
+        self.push(node, node);
+        self.collect();
+    }
+
+    // Pushes a chain of retired values
+    fn push(&self, first: *mut Retired<T>, last: *mut Retired<T>) {
+        let mut ll = self.retired.load_linked(ordering::Relaxed);
+        loop {
+            unsafe { (*last).next = ll.get() };
+            match ll.store_conditional(first, ordering::Release) {
+                Ok(()) => return,
+                Err(fail) => ll = fail.into_link(),
+            }
+        }
+    }
+
+    // Moves the phase on as far as the readers allow, up to twice,
+    // and drops whatever was retired at least two phases ago
+    fn collect(&self) {
+        for _ in 0..2 {
+            let phase = self.phase.load(SeqCst);
+            // The next phase reuses the slot of the one before this
+            if self.readers[phase.wrapping_add(1) & 1].load(SeqCst) != 0 {
+                break;
+            }
+            let _ = self.phase.compare_exchange(phase, phase.wrapping_add(1), SeqCst, Relaxed);
+        }
+
+        let mut ll = self.retired.load_linked(ordering::Acquire);
+        let mut cur = loop {
+            let head = ll.get();
+            if head.is_null() {
+                return;
+            }
+            match ll.store_conditional(ptr::null_mut(), ordering::Relaxed) {
+                Ok(()) => break head,
+                Err(fail) => ll = fail.into_link(),
+            }
+        };
+
+        let phase = self.phase.load(SeqCst);
+        let mut kept: (*mut Retired<T>, *mut Retired<T>) = (ptr::null_mut(), ptr::null_mut());
+        while !cur.is_null() {
+            let node = cur;
+            unsafe {
+                cur = (*node).next;
+                if phase.wrapping_sub((*node).phase) >= 2 {
+                    drop(Box::from_raw(node).val);
+                } else {
+                    (*node).next = kept.0;
+                    if kept.1.is_null() {
+                        kept.1 = node;
+                    }
+                    kept.0 = node;
+                }
+            }
+        }
+        if !kept.0.is_null() {
+            self.push(kept.0, kept.1);
+        }
+    }
+}
+
+impl<T> Drop for Grace<T> {
+    fn drop(&mut self) {
+        let mut cur = self.retired.load(Relaxed);
+        while !cur.is_null() {
+            let node = unsafe { Box::from_raw(cur) };
+            cur = node.next;
+            drop(node.val);
+        }
+    }
+}
+
+impl<'a, T> Drop for GracePin<'a, T> {
+    fn drop(&mut self) {
+        self.grace.readers[self.slot].fetch_sub(1, Release);
+    }
+}
@@ -0,0 +1,300 @@
+//! Hazard pointer reclamation for ExclusivePtr
+//!
+//! A HazardGuard owns one hazard record in a HazardDomain. Protecting a cell
+//! publishes its pointer in the record, then takes a link and only hands it
+//! out if the link still holds that pointer, so the pointer can't have been
+//! retired in between and any store_conditional through the link fails if
+//! the cell changed since. Retired pointers are freed once no record holds
+//! them, which is checked whenever the retired list of a record outgrows
+//! the number of records. That bounds the garbage left behind by each thread,
+//! even when a thread is preempted while holding a hazard.
+
+use std::ptr;
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicUsize, AtomicBool, fence};
+use std::sync::atomic::Ordering::{Relaxed, Acquire, Release, SeqCst};
+
+use ordering;
+use {ExclusivePtr, LinkedPtr};
+
+// Scanning is skipped until a record has this many more
+// retired pointers than there are records
+const RETIRE_SLACK: usize = 64;
+
+struct Retired {
+    ptr: usize,
+    free: unsafe fn(usize),
+}
+
+struct Record {
+    hazard: AtomicUsize,
+    active: AtomicBool,
+    next: *mut Record,
+    // Only touched by whoever holds the record active
+    retired: UnsafeCell<Vec<Retired>>,
+}
+
+pub struct HazardDomain {
+    records: ExclusivePtr<Record>,
+    num_records: AtomicUsize,
+}
+
+/// A single hazard pointer, borrowed from a domain
+pub struct HazardGuard<'d> {
+    domain: &'d HazardDomain,
+    record: *const Record,
+}
+
+/// A link whose value is protected from reclamation for as long as it lives
+pub struct Protected<'a, T: 'a> {
+    link: LinkedPtr<'a, T>,
+}
+
+unsafe fn free_box<T>(ptr: usize) {
+    drop(Box::from_raw(ptr as *mut T));
+}
+
+impl HazardDomain {
+
+    pub const fn new() -> HazardDomain {
+        HazardDomain {
+            records: ExclusivePtr::NULL,
+            num_records: AtomicUsize::new(0),
+        }
+    }
+
+    /// Takes a hazard record, reusing an idle one when possible
+    pub fn guard(&self) -> HazardGuard<'_> {
+        let mut cur = self.records.load(Acquire);
+        while !cur.is_null() {
+            let rec = unsafe { &*cur };
+            if !rec.active.load(Relaxed) &&
+               rec.active.compare_exchange(false, true, Acquire, Relaxed).is_ok() {
+                return HazardGuard {
+                    domain: self,
+                    record: rec,
+                };
+            }
+            cur = rec.next;
+        }
+
+        let rec = Box::into_raw(Box::new(Record {
+            hazard: AtomicUsize::new(0),
+            active: AtomicBool::new(true),
+            next: ptr::null_mut(),
+            retired: UnsafeCell::new(Vec::new()),
+        }));
+        let mut ll = self.records.load_linked(ordering::Relaxed);
+        loop {
+            unsafe { (*rec).next = ll.get() };
+            match ll.store_conditional(rec, ordering::Release) {
+                Ok(()) => break,
+                Err(fail) => ll = fail.into_link(),
+            }
+        }
+        self.num_records.fetch_add(1, Relaxed);
+        HazardGuard {
+            domain: self,
+            record: rec,
+        }
+    }
+
+    // Frees every retired pointer which no record is protecting
+    fn scan(&self, retired: &mut Vec<Retired>) {
+        // Orders the unlinking stores before the hazards are read,
+        // pairing with the SeqCst store and link in protect
+        fence(SeqCst);
+        let mut hazards = Vec::new();
+        let mut cur = self.records.load(Acquire);
+        while !cur.is_null() {
+            let rec = unsafe { &*cur };
+            let hazard = rec.hazard.load(SeqCst);
+            if hazard != 0 {
+                hazards.push(hazard);
+            }
+            cur = rec.next;
+        }
+        hazards.sort();
+        retired.retain(|r| {
+            if hazards.binary_search(&r.ptr).is_ok() {
+                return true;
+            }
+            unsafe { (r.free)(r.ptr) };
+            false
+        });
+    }
+}
+
+impl Default for HazardDomain {
+    fn default() -> HazardDomain {
+        HazardDomain::new()
+    }
+}
+
+impl Drop for HazardDomain {
+    fn drop(&mut self) {
+        let mut cur = self.records.load(Relaxed);
+        while !cur.is_null() {
+            let rec = unsafe { Box::from_raw(cur) };
+            for r in unsafe { &*rec.retired.get() } {
+                unsafe { (r.free)(r.ptr) };
+            }
+            cur = rec.next;
+        }
+    }
+}
+
+unsafe impl Send for HazardDomain {}
+unsafe impl Sync for HazardDomain {}
+
+impl<'d> HazardGuard<'d> {
+
+    fn record(&self) -> &Record {
+        unsafe { &*self.record }
+    }
+
+    /// Protects the value of the cell, returning a link to it
+    ///
+    /// Only one value is protected at a time, so the guard stays borrowed
+    /// for as long as the link is alive
+    pub fn protect<'a, T>(&'a mut self, cell: &'a ExclusivePtr<T>) -> Protected<'a, T> {
+        let mut cur = cell.load(Relaxed);
+        loop {
+            self.record().hazard.store(cur as usize, SeqCst);
+            let link = cell.load_linked(ordering::SeqCst);
+            let now = link.get();
+            if now == cur {
+                return Protected { link };
+            }
+            cur = now;
+        }
+    }
+
+    /// Stops protecting anything
+    pub fn clear(&mut self) {
+        self.record().hazard.store(0, Release);
+    }
+
+    /// Frees the pointer once no hazard protects it
+    ///
+    /// # Safety
+    ///
+    /// The pointer must have come from a Box, and must already be unreachable
+    /// from every cell, so that no new hazard can be taken on it
+    pub unsafe fn retire<T>(&self, ptr: *mut T) {
+        if ptr.is_null() {
+            return;
+        }
+        let retired = &mut *self.record().retired.get();
+        retired.push(Retired {
+            ptr: ptr as usize,
+            free: free_box::<T>,
+        });
+        let bound = RETIRE_SLACK + 2 * self.domain.num_records.load(Relaxed);
+        if retired.len() >= bound {
+            self.domain.scan(retired);
+        }
+    }
+}
+
+impl<'d> Drop for HazardGuard<'d> {
+    fn drop(&mut self) {
+        // The retired list stays with the record for its next owner
+        let rec = self.record();
+        rec.hazard.store(0, Release);
+        rec.active.store(false, Release);
+    }
+}
+
+impl<'a, T> Protected<'a, T> {
+
+    pub fn get(&self) -> Option<&T> {
+        unsafe { self.link.get().as_ref() }
+    }
+
+    pub fn as_raw(&self) -> *mut T {
+        self.link.get()
+    }
+
+    /// Returns the link, to store_conditional through
+    pub fn into_link(self) -> LinkedPtr<'a, T> {
+        self.link
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate crossbeam;
+    use self::crossbeam::scope;
+    use super::*;
+    use test_util::Counted;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::{Relaxed, SeqCst};
+
+    #[test]
+    fn test_protect_retire() {
+        let drops = AtomicUsize::new(0);
+        let domain = HazardDomain::new();
+        let cell = ExclusivePtr::new(Box::into_raw(Box::new(Counted(vec![1], &drops))));
+        let mut reader = domain.guard();
+        let writer = domain.guard();
+        {
+            let prot = reader.protect(&cell);
+            assert_eq!(prot.get().unwrap().0[0], 1);
+            let old = prot.as_raw();
+            let new = Box::into_raw(Box::new(Counted(vec![2], &drops)));
+            cell.load_linked(Relaxed).store_conditional(new, Relaxed).unwrap();
+            unsafe { writer.retire(old) };
+            domain.scan(unsafe { &mut *writer.record().retired.get() });
+            // Still protected by the reader
+            assert_eq!(drops.load(SeqCst), 0);
+            assert_eq!(prot.get().unwrap().0[0], 1);
+            assert!(prot.into_link().store_conditional(ptr::null_mut(), Relaxed).is_err());
+        }
+        reader.clear();
+        domain.scan(unsafe { &mut *writer.record().retired.get() });
+        assert_eq!(drops.load(SeqCst), 1);
+        unsafe { writer.retire(cell.load(Relaxed)) };
+        drop(writer);
+        drop(reader);
+        drop(domain);
+        assert_eq!(drops.load(SeqCst), 2);
+    }
+
+    #[test]
+    fn test_mt_replace() {
+        let num_run: usize = 10000;
+        let drops = AtomicUsize::new(0);
+        let domain = HazardDomain::new();
+        let cell = ExclusivePtr::new(Box::into_raw(Box::new(Counted(vec![0; 16], &drops))));
+
+        scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    let mut guard = domain.guard();
+                    for i in 0..num_run {
+                        let new = Box::into_raw(Box::new(Counted(vec![i; 16], &drops)));
+                        loop {
+                            let prot = guard.protect(&cell);
+                            let v = &prot.get().unwrap().0;
+                            assert!(v.iter().all(|x| *x == v[0]));
+                            let old = prot.as_raw();
+                            if prot.into_link().store_conditional(new, Relaxed).is_ok() {
+                                unsafe { guard.retire(old) };
+                                break;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        let guard = domain.guard();
+        unsafe { guard.retire(cell.load(Relaxed)) };
+        // Garbage is bounded by the scan threshold of the four records
+        assert!(4 * num_run + 1 - drops.load(SeqCst) <= 4 * (RETIRE_SLACK + 2 * 4) + 1);
+        drop(guard);
+        drop(domain);
+        assert_eq!(drops.load(SeqCst), 4 * num_run + 1);
+    }
+}
@@ -1,4 +1,6 @@
-#![feature(asm)]
+#![cfg_attr(target_arch = "powerpc", feature(asm_experimental_arch))]
+
+extern crate crossbeam;
 
 #[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
 mod exclusive_target {
@@ -6,6 +8,8 @@ mod exclusive_target {
     //mod x86;
     pub use self::cas_impl::{ExclusivePtr, ExclusiveUsize, ExclusiveIsize, ExclusiveBool};
     pub use self::cas_impl::{LinkedPtr, LinkedUsize, LinkedIsize, LinkedBool};
+    pub use self::cas_impl::{ExclusiveData, LinkedData, ScFailure};
+    pub use self::cas_impl::IsUsize;
     pub const IS_LOCK_FREE: bool = true;
 }
 
@@ -14,6 +18,8 @@ mod exclusive_target {
     mod llsc_impl;
     pub use self::llsc_impl::{ExclusivePtr, ExclusiveUsize, ExclusiveIsize, ExclusiveBool};
     pub use self::llsc_impl::{LinkedPtr, LinkedUsize, LinkedIsize, LinkedBool};
+    pub use self::llsc_impl::{ExclusiveData, LinkedData, ScFailure};
+    pub use self::llsc_impl::IsUsize;
     pub const IS_LOCK_FREE: bool = true;
 }
 
@@ -25,6 +31,8 @@ mod exclusive_target {
 mod exclusive_target {
     pub use super::generic::{ExclusivePtr, ExclusiveUsize, ExclusiveIsize, ExclusiveBool};
     pub use super::generic::{LinkedPtr, LinkedUsize, LinkedIsize, LinkedBool};
+    pub use super::generic::{ExclusiveData, LinkedData, ScFailure};
+    pub use super::generic::IsUsize;
     pub const IS_LOCK_FREE: bool = false;
 }
 
@@ -32,9 +40,48 @@ mod exclusive_target {
 #[allow(dead_code)]
 mod generic;
 
+pub mod ordering;
+mod failure;
+mod marked;
+mod grace;
+mod exclusive_box;
+mod exclusive_shared;
+mod exclusive_arc;
+mod cache_padded;
+mod thread_index;
+mod exclusive_array;
+mod hazard;
+mod epoch;
+pub mod collections;
+pub mod pool;
+pub mod combining;
+pub mod universal;
+mod wait_free;
+mod counter;
+mod opt_lock;
+
+#[cfg(test)]
+mod litmus;
+#[cfg(test)]
+mod test_util;
+
 
 pub use self::exclusive_target::{ExclusivePtr, ExclusiveUsize, ExclusiveIsize, ExclusiveBool};
 pub use self::exclusive_target::{LinkedPtr, LinkedUsize, LinkedIsize, LinkedBool};
+pub use self::exclusive_target::{ExclusiveData, LinkedData};
+pub use self::exclusive_target::ScFailure;
+pub(crate) use self::exclusive_target::IsUsize;
+pub use self::failure::FailureReason;
+pub use self::exclusive_box::{ExclusiveBox, BoxGuard, LinkedBox, BoxFailure};
+pub use self::exclusive_shared::{ExclusiveShared, LinkedShared, SharedFailure};
+pub use self::epoch::{pin, Guard};
+pub use self::exclusive_arc::ExclusiveArc;
+pub use self::cache_padded::{CachePadded, CACHE_LINE, RESERVATION_GRANULE};
+pub use self::exclusive_array::{ExclusiveArray, ArrayIter, Padding};
+pub use self::hazard::{HazardDomain, HazardGuard, Protected};
+pub use self::wait_free::WaitFreeCell;
+pub use self::counter::ShardedCounter;
+pub use self::opt_lock::{OptLock, Version, WriteGuard};
 
 #[inline(always)]
 pub fn is_lock_free() -> bool {
@@ -54,7 +101,7 @@ mod test {
         let eptr = ExclusivePtr::<usize>::new(ptr::null_mut());
         let ll = eptr.load_linked(Relaxed);
         assert_eq!(eptr.load(Relaxed), ptr::null_mut());
-        assert_eq!(ll.try_store_conditional(&mut val, Relaxed), true);
+        assert!(ll.try_store_conditional(&mut val, Relaxed));
         assert_eq!(eptr.load(Relaxed), &mut val as *mut usize);
     }
 
@@ -67,7 +114,7 @@ mod test {
         assert_eq!(eptr.load(Relaxed), ptr::null_mut());
         eptr.store_direct(&mut val2, Relaxed);
         assert_eq!(eptr.load(Relaxed), &mut val2 as *mut usize);
-        assert_eq!(ll.store_conditional(&mut val, Relaxed).is_some(), true);
+        assert!(ll.store_conditional(&mut val, Relaxed).is_err());
         assert_eq!(eptr.load(Relaxed), &mut val2 as *mut usize);
     }
 
@@ -80,10 +127,122 @@ mod test {
         assert_eq!(eptr.load(Relaxed), ptr::null_mut());
         assert_eq!(eptr.exchange_direct(&mut val2, Relaxed), ptr::null_mut());
         assert_eq!(eptr.load(Relaxed), &mut val2 as *mut usize);
-        assert_eq!(ll.store_conditional(&mut val, Relaxed).is_some(), true);
+        assert!(ll.store_conditional(&mut val, Relaxed).is_err());
         assert_eq!(eptr.load(Relaxed), &mut val2 as *mut usize);
     }
 
+    #[test]
+    fn test_typed_orderings () {
+        use ordering::{Acquire, Release, SeqCst};
+        let val = ExclusiveUsize::new(0);
+        let ll = val.load_linked(Acquire);
+        assert!(ll.store_conditional(1, Release).is_ok());
+        let ll = val.load_linked(SeqCst);
+        assert_eq!(ll.get(), 1);
+        assert!(ll.try_store_conditional(2, SeqCst));
+        assert_eq!(val.load(Relaxed), 2);
+    }
+
+    #[test]
+    fn test_explicit_failure () {
+        use ordering::{Acquire, Release};
+        let val = ExclusiveUsize::new(0);
+        let ll = val.load_linked(Relaxed);
+        val.load_linked(Relaxed).store_conditional(1, Relaxed).unwrap();
+        let nll = ll.store_conditional_explicit(2, Release, Acquire).unwrap_err().into_link();
+        assert_eq!(nll.get(), 1);
+        assert!(nll.store_conditional_explicit(2, Release, Acquire).is_ok());
+        assert_eq!(val.load(Relaxed), 2);
+    }
+
+    #[test]
+    fn test_failure_reason () {
+        let val = ExclusiveUsize::new(0);
+        let ll = val.load_linked(Relaxed);
+        val.load_linked(Relaxed).store_conditional(1, Relaxed).unwrap();
+        let fail = ll.store_conditional(2, Relaxed).unwrap_err();
+        assert_eq!(fail.reason(), FailureReason::ValueChanged);
+
+        let ll = fail.into_link();
+        val.load_linked(Relaxed).store_conditional(1, Relaxed).unwrap();
+        let fail = ll.store_conditional(2, Relaxed).unwrap_err();
+        assert!(fail.value_unchanged());
+        assert_eq!(val.load(Relaxed), 1);
+        // Without a counter the same value stored again can't be told apart
+        if cfg!(target_arch = "powerpc") {
+            assert_eq!(fail.reason(), FailureReason::ReservationLost);
+        } else {
+            assert_eq!(fail.reason(), FailureReason::VersionChanged);
+        }
+
+        // Linking another cell takes the reservation on ll/sc hardware, although
+        // the store may still succeed where the monitor only tracks a granule
+        let other = ExclusiveUsize::new(0);
+        let ll = val.load_linked(Relaxed);
+        let _ = other.load_linked(Relaxed);
+        match ll.store_conditional(2, Relaxed) {
+            Ok(()) => assert_eq!(val.load(Relaxed), 2),
+            Err(fail) if cfg!(any(target_arch = "aarch64", target_arch = "arm")) => {
+                assert_eq!(fail.reason(), FailureReason::Spurious);
+            },
+            Err(fail) if cfg!(target_arch = "powerpc") => {
+                assert_eq!(fail.reason(), FailureReason::ReservationLost);
+            },
+            Err(fail) => panic!("cmpxchg failed without a conflict: {:?}", fail),
+        }
+    }
+
+    #[test]
+    fn test_bool () {
+        let flag = ExclusiveBool::new(true);
+        assert!(flag.load(Relaxed));
+        let ll = flag.load_linked(Relaxed);
+        assert!(ll.get());
+        assert!(ll.store_conditional(false, Relaxed).is_ok());
+        assert!(!flag.load(Relaxed));
+        assert!(!flag.load_linked(Relaxed).get());
+        assert!(FLAG.load(Relaxed));
+    }
+
+    static COUNT: ExclusiveUsize = ExclusiveUsize::new(0);
+    static FLAG: ExclusiveBool = ExclusiveBool::new(true);
+    static HEAD: ExclusivePtr<usize> = ExclusivePtr::NULL;
+
+    #[test]
+    fn test_statics () {
+        let mut val: usize = 0;
+        assert_eq!(HEAD.load(Relaxed), ptr::null_mut());
+        assert!(HEAD.load_linked(Relaxed).store_conditional(&mut val, Relaxed).is_ok());
+        assert_eq!(HEAD.load(Relaxed), &mut val as *mut usize);
+        HEAD.store_direct(ptr::null_mut(), Relaxed);
+
+        scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..1000 {
+                        let mut ll = COUNT.load_linked(Relaxed);
+                        loop {
+                            let next = ll.get() + 1;
+                            match ll.store_conditional(next, Relaxed) {
+                                Ok(()) => break,
+                                Err(fail) => ll = fail.into_link(),
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(COUNT.load(Relaxed), 4000);
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+    fn test_layout () {
+        use std::mem;
+        assert_eq!(mem::size_of::<ExclusiveUsize>(), 2 * mem::size_of::<usize>());
+        assert_eq!(mem::align_of::<ExclusiveUsize>(), 2 * mem::size_of::<usize>());
+    }
+
     #[test]
     fn test_mt_cas() {
         let num_run: usize = 10000;
@@ -98,8 +257,8 @@ mod test {
                         loop {
                             let next = ll.get() + 1;
                             match ll.store_conditional(next, Relaxed) {
-                                None => break,
-                                Some(nll) => ll = nll,
+                                Ok(()) => break,
+                                Err(fail) => ll = fail.into_link(),
                             }
                         }
                     }
@@ -0,0 +1,114 @@
+//! Litmus tests for the orderings of load_linked and store_conditional
+//!
+//! Every backend guarantees that:
+//!
+//! * A store_conditional with Release or SeqCst publishes all earlier writes
+//!   to any load_linked or load with Acquire or SeqCst that sees its value
+//! * The link returned from a failed store_conditional is loaded with the
+//!   failure ordering, so an Acquire failure also sees those writes
+//! * The value and counter of a link always come from the same store
+//! * SeqCst links and stores take part in the single total order of SeqCst
+//!   operations, so the store buffering outcome below is forbidden
+//!
+//! The outcomes checked here can not happen on x86 regardless, but the
+//! weaker orderings are observable on arm, aarch64 and powerpc.
+
+extern crate crossbeam;
+use self::crossbeam::scope;
+use super::*;
+use ordering::{Acquire, Release, SeqCst};
+use std::sync::Barrier;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+
+const NUM_RUN: usize = 100000;
+
+fn publish(data: &AtomicUsize, flag: &ExclusiveUsize, i: usize) {
+    data.store(i, Relaxed);
+    let mut ll = flag.load_linked(Relaxed);
+    loop {
+        match ll.store_conditional(i, Release) {
+            Ok(()) => break,
+            Err(fail) => ll = fail.into_link(),
+        }
+    }
+}
+
+#[test]
+fn test_message_passing() {
+    let data = AtomicUsize::new(0);
+    let flag = ExclusiveUsize::new(0);
+
+    scope(|scope| {
+        scope.spawn(|| {
+            for i in 1..NUM_RUN {
+                publish(&data, &flag, i);
+            }
+        });
+        scope.spawn(|| {
+            for _ in 1..NUM_RUN {
+                let seen = flag.load_linked(Acquire).get();
+                assert!(data.load(Relaxed) >= seen);
+            }
+        });
+    });
+}
+
+#[test]
+fn test_failure_acquire() {
+    let data = AtomicUsize::new(0);
+    let flag = ExclusiveUsize::new(0);
+
+    scope(|scope| {
+        scope.spawn(|| {
+            for i in 1..NUM_RUN {
+                publish(&data, &flag, i);
+            }
+        });
+        scope.spawn(|| {
+            for _ in 1..NUM_RUN {
+                // The link itself is Relaxed, only the reload may be relied upon
+                let ll = flag.load_linked(Relaxed);
+                let cur = ll.get();
+                if let Err(fail) = ll.store_conditional_explicit(cur, Relaxed, Acquire) {
+                    assert!(data.load(Relaxed) >= fail.into_link().get());
+                }
+            }
+        });
+    });
+}
+
+#[test]
+fn test_store_buffering() {
+    let num_run = NUM_RUN / 100;
+    let x = ExclusiveUsize::new(0);
+    let y = ExclusiveUsize::new(0);
+    let barrier = Barrier::new(2);
+    let results = [AtomicUsize::new(0), AtomicUsize::new(0)];
+
+    let run = |mine: &ExclusiveUsize, other: &ExclusiveUsize, res: &AtomicUsize, check: bool| {
+        for i in 1..num_run {
+            barrier.wait();
+            let mut ll = mine.load_linked(Relaxed);
+            loop {
+                match ll.store_conditional(i, SeqCst) {
+                    Ok(()) => break,
+                    Err(fail) => ll = fail.into_link(),
+                }
+            }
+            res.store(other.load_linked(SeqCst).get(), Relaxed);
+            barrier.wait();
+            // Neither thread may miss the store of the other one
+            if check {
+                let r0 = results[0].load(Relaxed);
+                let r1 = results[1].load(Relaxed);
+                assert!(r0 == i || r1 == i);
+            }
+        }
+    };
+
+    scope(|scope| {
+        scope.spawn(|| run(&x, &y, &results[0], true));
+        scope.spawn(|| run(&y, &x, &results[1], false));
+    });
+}
@@ -0,0 +1,84 @@
+//! Mark bit accessors for ExclusivePtr
+//!
+//! The low bit of a pointer to anything aligned to 2 or more is always zero,
+//! so lock-free structures use it as a flag stored atomically with the pointer,
+//! like the logical deletion mark of a Harris list. These accessors split
+//! the bit from the pointer, and plain load and get still return both together.
+
+use std::mem;
+use std::sync::atomic::Ordering;
+
+use ordering::StoreOrdering;
+use {ExclusiveData, LinkedData, ScFailure};
+
+const MARK: usize = 1;
+
+#[inline(always)]
+fn with_mark<T>(ptr: *mut T, mark: bool) -> *mut T {
+    debug_assert!(mem::align_of::<T>() > MARK, "marked pointers need an alignment of 2 or more");
+    debug_assert!(ptr as usize & MARK == 0, "the pointer is already marked");
+    (ptr as usize | mark as usize) as *mut T
+}
+
+#[inline(always)]
+fn split_mark<T>(ptr: *mut T) -> (*mut T, bool) {
+    ((ptr as usize & !MARK) as *mut T, ptr as usize & MARK != 0)
+}
+
+impl<T> ExclusiveData<*mut T> {
+
+    /// Loads the pointer with the mark bit cleared, along with the mark
+    pub fn load_marked(&self, ord: Ordering) -> (*mut T, bool) {
+        split_mark(self.load(ord))
+    }
+}
+
+impl<'a, T> LinkedData<'a, *mut T> {
+
+    /// Returns the linked pointer with the mark bit cleared, along with the mark
+    pub fn get_marked(&self) -> (*mut T, bool) {
+        split_mark(self.get())
+    }
+
+    pub fn is_marked(&self) -> bool {
+        self.get_marked().1
+    }
+
+    /// Performs a store_conditional of the pointer with the mark set to mark
+    pub fn store_conditional_marked<O: StoreOrdering>(self, ptr: *mut T, mark: bool, ord: O)
+                                                      -> Result<(), ScFailure<'a, *mut T>> {
+        self.store_conditional(with_mark(ptr, mark), ord)
+    }
+
+    /// Performs a try_store_conditional of the pointer with the mark set to mark
+    pub fn try_store_conditional_marked<O: StoreOrdering>(self, ptr: *mut T, mark: bool, ord: O)
+                                                          -> bool {
+        self.try_store_conditional(with_mark(ptr, mark), ord)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ExclusivePtr;
+    use ordering::Relaxed;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn test_marks() {
+        let mut val = 5usize;
+        let ptr = &mut val as *mut usize;
+        let cell = ExclusivePtr::new(ptr);
+        assert_eq!(cell.load_marked(Ordering::Relaxed), (ptr, false));
+
+        let ll = cell.load_linked(Relaxed);
+        assert!(!ll.is_marked());
+        ll.store_conditional_marked(ptr, true, Relaxed).unwrap();
+        assert_eq!(cell.load_marked(Ordering::Relaxed), (ptr, true));
+        assert!(cell.load(Ordering::Relaxed) != ptr);
+
+        let ll = cell.load_linked(Relaxed);
+        assert_eq!(ll.get_marked(), (ptr, true));
+        assert!(ll.try_store_conditional_marked(ptr, false, Relaxed));
+        assert_eq!(cell.load(Ordering::Relaxed), ptr);
+    }
+}
@@ -0,0 +1,277 @@
+//! A versioned lock for optimistic lock coupling
+//!
+//! Readers don't take the lock at all. They record the version, read the
+//! data, and then validate that the version didn't change, retrying the read
+//! if it did. Writers lock by setting a bit in the word, and bump the sequence
+//! in the rest of it when they unlock, which fails every validation of a
+//! version from before. A lock can also be marked obsolete once its data is
+//! unlinked, after which every validation fails and it can't be locked again.
+//!
+//! The word is an ExclusiveUsize and every change to it is a store_conditional,
+//! so the counter of the cell moves on with every lock and unlock. A version
+//! holds the counter as well as the word, so it doesn't validate even if the
+//! sequence wrapped all the way around. powerpc has no counter, and there the
+//! sequence bits in the word have to do.
+//!
+//! Reads done between read_begin and validate can see data in the middle of
+//! being written, so they should only be loads of atomics, and nothing read
+//! should be trusted before validate passes.
+
+use std::thread;
+use std::sync::atomic::fence;
+use std::sync::atomic::Ordering::{Relaxed, Acquire, Release};
+
+use ordering;
+use ExclusiveUsize;
+
+// Spins on a locked word before yielding
+const SPINS: usize = 64;
+
+const LOCKED: usize = 1;
+const OBSOLETE: usize = 2;
+const SEQUENCE: usize = 4;
+
+/// The state of an OptLock as seen by read_begin
+///
+/// On backends with a link counter a version never comes back. powerpc has
+/// none, so only the 30 sequence bits of the word are compared there, and
+/// 2^30 writes during a single read bring the version around again.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Version {
+    word: usize,
+    counter: usize,
+}
+
+impl Version {
+
+    /// Whether the lock was obsolete, so the read should restart elsewhere
+    pub fn is_obsolete(&self) -> bool {
+        self.word & OBSOLETE != 0
+    }
+}
+
+pub struct OptLock {
+    word: ExclusiveUsize,
+}
+
+/// Holds an OptLock locked, unlocking it with a new version when dropped
+pub struct WriteGuard<'a> {
+    lock: &'a OptLock,
+    obsolete: bool,
+}
+
+impl OptLock {
+
+    pub const fn new() -> OptLock {
+        OptLock {
+            word: ExclusiveUsize::new(0),
+        }
+    }
+
+    /// Waits until the lock is unlocked and returns its version
+    pub fn read_begin(&self) -> Version {
+        let mut spins = 0;
+        loop {
+            let version = self.version();
+            if version.word & LOCKED == 0 {
+                return version;
+            }
+            spins += 1;
+            if spins % SPINS == 0 {
+                thread::yield_now();
+            }
+        }
+    }
+
+    /// Whether nothing was written since the version was read,
+    /// which is never the case for an obsolete version
+    ///
+    /// On powerpc a read which takes 2^30 writes validates anyway, see Version
+    pub fn validate(&self, version: Version) -> bool {
+        // Keeps the reads of the data from moving past the check
+        fence(Acquire);
+        !version.is_obsolete() && self.version() == version
+    }
+
+    /// Locks the lock if nothing was written since the version was read
+    pub fn upgrade(&self, version: Version) -> Option<WriteGuard<'_>> {
+        // The counter is checked before linking, since loads between the link and
+        // the store_conditional can break hardware links. The sequence can't wrap
+        // in the few instructions in between, so the word check covers them
+        if version.is_obsolete() || self.version() != version {
+            return None;
+        }
+        let mut ll = self.word.load_linked(ordering::Acquire);
+        loop {
+            if ll.get() != version.word {
+                return None;
+            }
+            match ll.store_conditional(version.word | LOCKED, ordering::Relaxed) {
+                Ok(()) => return Some(WriteGuard::new(self)),
+                Err(fail) => ll = fail.into_link(),
+            }
+        }
+    }
+
+    /// Waits until the lock is unlocked and locks it,
+    /// or returns None if it is obsolete
+    pub fn write_lock(&self) -> Option<WriteGuard<'_>> {
+        let mut spins = 0;
+        let mut ll = self.word.load_linked(ordering::Acquire);
+        loop {
+            let word = ll.get();
+            if word & OBSOLETE != 0 {
+                return None;
+            }
+            if word & LOCKED == 0 {
+                match ll.store_conditional(word | LOCKED, ordering::Relaxed) {
+                    Ok(()) => return Some(WriteGuard::new(self)),
+                    Err(fail) => ll = fail.into_link(),
+                }
+                continue;
+            }
+            spins += 1;
+            if spins % SPINS == 0 {
+                thread::yield_now();
+            }
+            ll = self.word.load_linked(ordering::Acquire);
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.word.load(Relaxed) & LOCKED != 0
+    }
+
+    pub fn is_obsolete(&self) -> bool {
+        self.word.load(Relaxed) & OBSOLETE != 0
+    }
+
+    fn version(&self) -> Version {
+        let (word, counter) = self.word.load_counted(Acquire);
+        Version {
+            word,
+            counter,
+        }
+    }
+}
+
+impl Default for OptLock {
+    fn default() -> OptLock {
+        OptLock::new()
+    }
+}
+
+impl<'a> WriteGuard<'a> {
+
+    fn new(lock: &'a OptLock) -> WriteGuard<'a> {
+        // Keeps the writes to the data from showing before the locked word,
+        // where a reader could validate a version from before them
+        fence(Release);
+        WriteGuard {
+            lock,
+            obsolete: false,
+        }
+    }
+
+    /// Marks the lock obsolete once the guard is dropped,
+    /// for data which was unlinked while it was locked
+    pub fn mark_obsolete(&mut self) {
+        self.obsolete = true;
+    }
+}
+
+impl<'a> Drop for WriteGuard<'a> {
+    fn drop(&mut self) {
+        let obsolete = match self.obsolete {
+            true => OBSOLETE,
+            false => 0,
+        };
+        // Only the holder writes a locked word, so this only retries spurious failures
+        let mut ll = self.lock.word.load_linked(ordering::Relaxed);
+        loop {
+            let word = (ll.get() & !LOCKED).wrapping_add(SEQUENCE) | obsolete;
+            match ll.store_conditional(word, ordering::Release) {
+                Ok(()) => return,
+                Err(fail) => ll = fail.into_link(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crossbeam::scope;
+    use std::sync::atomic::AtomicUsize;
+    use super::*;
+
+    #[test]
+    fn test_versions() {
+        let lock = OptLock::new();
+        let v = lock.read_begin();
+        assert!(lock.validate(v));
+        drop(lock.write_lock());
+        assert!(!lock.validate(v));
+        assert!(lock.upgrade(v).is_none());
+
+        let v = lock.read_begin();
+        {
+            let _guard = lock.upgrade(v).unwrap();
+            assert!(lock.is_locked());
+            assert!(!lock.validate(v));
+        }
+        assert!(!lock.is_locked());
+
+        let v = lock.read_begin();
+        lock.write_lock().unwrap().mark_obsolete();
+        assert!(lock.is_obsolete());
+        assert!(!lock.validate(v));
+        let v = lock.read_begin();
+        assert!(v.is_obsolete());
+        assert!(!lock.validate(v));
+        assert!(lock.upgrade(v).is_none());
+        assert!(lock.write_lock().is_none());
+    }
+
+    // Writers move one unit from a to b, half of them by upgrading a read,
+    // so a reader which validates must never see a and b out of balance
+    #[test]
+    fn test_mt_optimistic() {
+        let num_run: usize = 20000;
+        let num_threads = 4;
+        let total = num_threads * num_run;
+        let lock = OptLock::new();
+        let a = AtomicUsize::new(total);
+        let b = AtomicUsize::new(0);
+        let validated = AtomicUsize::new(0);
+
+        scope(|scope| {
+            for t in 0..num_threads {
+                let (lock, a, b, validated) = (&lock, &a, &b, &validated);
+                scope.spawn(move || {
+                    for _ in 0..num_run {
+                        let v = lock.read_begin();
+                        let (ra, rb) = (a.load(Relaxed), b.load(Relaxed));
+                        if lock.validate(v) {
+                            assert_eq!(ra + rb, total);
+                            validated.fetch_add(1, Relaxed);
+                        }
+
+                        let mut guard = None;
+                        while guard.is_none() {
+                            guard = match t % 2 {
+                                0 => lock.upgrade(lock.read_begin()),
+                                _ => lock.write_lock(),
+                            };
+                        }
+                        a.store(a.load(Relaxed) - 1, Relaxed);
+                        b.store(b.load(Relaxed) + 1, Relaxed);
+                    }
+                });
+            }
+        });
+
+        assert_eq!((a.load(Relaxed), b.load(Relaxed)), (0, total));
+        assert!(validated.load(Relaxed) > 0);
+        assert!(!lock.is_locked());
+    }
+}
@@ -0,0 +1,123 @@
+//! Marker types for memory orderings checked at compile time
+//!
+//! `load_linked` and `store_conditional` are generic over the ordering they
+//! are given. Passing one of the markers in this module makes an invalid
+//! combination, like a `Release` load, fail to compile and lets each backend
+//! pick its fences statically. Passing a plain `Ordering` still works, and
+//! is checked at run time exactly like the std atomics do.
+//!
+//! ```compile_fail
+//! use exclusive_ptr::ExclusiveUsize;
+//! use exclusive_ptr::ordering::Release;
+//!
+//! let val = ExclusiveUsize::new(0);
+//! val.load_linked(Release);
+//! ```
+
+use std::sync::atomic::Ordering;
+
+/// Relaxed ordering, valid for both links and stores
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Relaxed;
+
+/// Acquire ordering, only valid for links
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Acquire;
+
+/// Release ordering, only valid for stores
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Release;
+
+/// Sequentially consistent ordering, valid for both links and stores
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SeqCst;
+
+/// An ordering which may be used for load_linked
+pub trait LoadOrdering: Copy {
+    fn load_ordering(self) -> Ordering;
+}
+
+/// An ordering which may be used for store_conditional
+pub trait StoreOrdering: Copy {
+    fn store_ordering(self) -> Ordering;
+}
+
+impl LoadOrdering for Relaxed {
+    #[inline(always)]
+    fn load_ordering(self) -> Ordering { Ordering::Relaxed }
+}
+
+impl LoadOrdering for Acquire {
+    #[inline(always)]
+    fn load_ordering(self) -> Ordering { Ordering::Acquire }
+}
+
+impl LoadOrdering for SeqCst {
+    #[inline(always)]
+    fn load_ordering(self) -> Ordering { Ordering::SeqCst }
+}
+
+impl StoreOrdering for Relaxed {
+    #[inline(always)]
+    fn store_ordering(self) -> Ordering { Ordering::Relaxed }
+}
+
+impl StoreOrdering for Release {
+    #[inline(always)]
+    fn store_ordering(self) -> Ordering { Ordering::Release }
+}
+
+impl StoreOrdering for SeqCst {
+    #[inline(always)]
+    fn store_ordering(self) -> Ordering { Ordering::SeqCst }
+}
+
+/// The run time checked wrapper, panics on Release and AcqRel
+impl LoadOrdering for Ordering {
+    #[inline(always)]
+    fn load_ordering(self) -> Ordering {
+        match self {
+            Ordering::Release | Ordering::AcqRel => panic!("Invalid load ordering"),
+            ord => ord,
+        }
+    }
+}
+
+/// The run time checked wrapper, panics on Acquire and AcqRel
+impl StoreOrdering for Ordering {
+    #[inline(always)]
+    fn store_ordering(self) -> Ordering {
+        match self {
+            Ordering::Acquire | Ordering::AcqRel => panic!("Invalid store ordering"),
+            ord => ord,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn test_markers() {
+        assert_eq!(Relaxed.load_ordering(), Ordering::Relaxed);
+        assert_eq!(Acquire.load_ordering(), Ordering::Acquire);
+        assert_eq!(SeqCst.load_ordering(), Ordering::SeqCst);
+        assert_eq!(Relaxed.store_ordering(), Ordering::Relaxed);
+        assert_eq!(Release.store_ordering(), Ordering::Release);
+        assert_eq!(SeqCst.store_ordering(), Ordering::SeqCst);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_release_load() {
+        Ordering::Release.load_ordering();
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_acquire_store() {
+        Ordering::Acquire.store_ordering();
+    }
+}
@@ -0,0 +1,368 @@
+//! A lock-free object pool
+//!
+//! Idle objects sit on an intrusive free list, each boxed together with its
+//! next pointer. Objects are only freed when the pool is, so a thread popping
+//! a stale head can always read its next pointer, and the store_conditional
+//! on the head fails if that object was taken and given back in between,
+//! which is the ABA that breaks a free list built on cas.
+//!
+//! With thread caches on, each thread also gets a slot holding a single idle
+//! object. Slots are picked by a per-thread index, so threads may share one
+//! when there are more threads than slots, which only costs a little contention.
+//! Once the free list runs dry get looks through every slot, so objects left
+//! in the slot of a thread which stopped using the pool aren't stranded there.
+
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+use std::thread;
+use std::sync::atomic::{AtomicUsize, AtomicPtr};
+use std::sync::atomic::Ordering::Relaxed;
+
+use ordering;
+use thread_index::thread_index;
+use ExclusivePtr;
+
+const CACHE_SLOTS: usize = 16;
+
+/// What get does when max_size objects are already in use
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// Creates an extra object, which is dropped instead of returned to the pool
+    Allocate,
+
+    /// Waits for an object to be returned
+    Block,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct PoolConfig {
+    /// The most objects the pool will create and keep, by default unbounded
+    pub max_size: usize,
+    pub overflow: Overflow,
+    pub thread_caches: bool,
+}
+
+impl Default for PoolConfig {
+    fn default() -> PoolConfig {
+        PoolConfig {
+            max_size: usize::MAX,
+            overflow: Overflow::Allocate,
+            thread_caches: false,
+        }
+    }
+}
+
+struct Entry<T> {
+    val: T,
+    // Atomic since a stale popper may read it while the entry is pushed again
+    next: AtomicPtr<Entry<T>>,
+}
+
+pub struct Pool<T> {
+    free: ExclusivePtr<Entry<T>>,
+    caches: Vec<ExclusivePtr<Entry<T>>>,
+    create: Box<dyn Fn() -> T + Send + Sync>,
+    config: PoolConfig,
+    created: AtomicUsize,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+/// An object taken from the pool, which goes back to it when dropped
+pub struct PoolGuard<'a, T: 'a> {
+    pool: &'a Pool<T>,
+    entry: *mut Entry<T>,
+    // Overflow objects aren't counted by the pool and are dropped on return
+    pooled: bool,
+}
+
+fn take_cached<T>(cache: &ExclusivePtr<Entry<T>>) -> *mut Entry<T> {
+    let mut ll = cache.load_linked(ordering::Acquire);
+    loop {
+        let entry = ll.get();
+        if entry.is_null() {
+            return entry;
+        }
+        match ll.store_conditional(ptr::null_mut(), ordering::Relaxed) {
+            Ok(()) => return entry,
+            Err(fail) => ll = fail.into_link(),
+        }
+    }
+}
+
+impl<T> Pool<T> {
+
+    /// Creates an unbounded pool without thread caches
+    pub fn new<F>(create: F) -> Pool<T>
+        where F: Fn() -> T + Send + Sync + 'static {
+        Pool::with_config(PoolConfig::default(), create)
+    }
+
+    pub fn with_config<F>(config: PoolConfig, create: F) -> Pool<T>
+        where F: Fn() -> T + Send + Sync + 'static {
+        let num_caches = match config.thread_caches {
+            true => CACHE_SLOTS,
+            false => 0,
+        };
+        Pool {
+            free: ExclusivePtr::NULL,
+            caches: (0..num_caches).map(|_| ExclusivePtr::NULL).collect(),
+            create: Box::new(create),
+            config,
+            created: AtomicUsize::new(0),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    /// Takes an idle object, or creates one if there are none
+    pub fn get(&self) -> PoolGuard<'_, T> {
+        loop {
+            let entry = self.take_idle();
+            if !entry.is_null() {
+                self.hits.fetch_add(1, Relaxed);
+                return self.guard(entry, true);
+            }
+            if self.created.fetch_add(1, Relaxed) < self.config.max_size {
+                self.misses.fetch_add(1, Relaxed);
+                return self.guard(self.alloc(), true);
+            }
+            self.created.fetch_sub(1, Relaxed);
+            match self.config.overflow {
+                Overflow::Allocate => {
+                    self.misses.fetch_add(1, Relaxed);
+                    return self.guard(self.alloc(), false);
+                },
+                Overflow::Block => thread::yield_now(),
+            }
+        }
+    }
+
+    /// The number of gets served by an idle object
+    pub fn hits(&self) -> usize {
+        self.hits.load(Relaxed)
+    }
+
+    /// The number of gets which had to create an object
+    pub fn misses(&self) -> usize {
+        self.misses.load(Relaxed)
+    }
+
+    fn guard(&self, entry: *mut Entry<T>, pooled: bool) -> PoolGuard<'_, T> {
+        PoolGuard {
+            pool: self,
+            entry,
+            pooled,
+        }
+    }
+
+    fn alloc(&self) -> *mut Entry<T> {
+        Box::into_raw(Box::new(Entry {
+            val: (self.create)(),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }))
+    }
+
+    fn cache(&self) -> Option<&ExclusivePtr<Entry<T>>> {
+        match self.caches.len() {
+            0 => None,
+            n => Some(&self.caches[thread_index() % n]),
+        }
+    }
+
+    fn take_idle(&self) -> *mut Entry<T> {
+        if let Some(cache) = self.cache() {
+            let entry = take_cached(cache);
+            if !entry.is_null() {
+                return entry;
+            }
+        }
+        let entry = self.pop_free();
+        if !entry.is_null() {
+            return entry;
+        }
+        // A thread which stopped using the pool may have left one in its slot
+        for cache in &self.caches {
+            let entry = take_cached(cache);
+            if !entry.is_null() {
+                return entry;
+            }
+        }
+        ptr::null_mut()
+    }
+
+    // The entry may be taken and given back after it is linked,
+    // which rewrites next, but then the store_conditional fails
+    fn pop_free(&self) -> *mut Entry<T> {
+        let mut ll = self.free.load_linked(ordering::Acquire);
+        loop {
+            let entry = ll.get();
+            if entry.is_null() {
+                return entry;
+            }
+            let next = unsafe { (*entry).next.load(Relaxed) };
+            match ll.store_conditional(next, ordering::Relaxed) {
+                Ok(()) => return entry,
+                Err(fail) => ll = fail.into_link(),
+            }
+        }
+    }
+
+    fn give_back(&self, entry: *mut Entry<T>) {
+        if let Some(cache) = self.cache() {
+            let ll = cache.load_linked(ordering::Relaxed);
+            if ll.get().is_null() && ll.try_store_conditional(entry, ordering::Release) {
+                return;
+            }
+        }
+        let mut ll = self.free.load_linked(ordering::Relaxed);
+        loop {
+            unsafe { (*entry).next.store(ll.get(), Relaxed) };
+            match ll.store_conditional(entry, ordering::Release) {
+                Ok(()) => return,
+                Err(fail) => ll = fail.into_link(),
+            }
+        }
+    }
+}
+
+impl<T> Drop for Pool<T> {
+    fn drop(&mut self) {
+        for cache in &self.caches {
+            let entry = cache.load(Relaxed);
+            if !entry.is_null() {
+                unsafe { drop(Box::from_raw(entry)) };
+            }
+        }
+        let mut entry = self.free.load(Relaxed);
+        while !entry.is_null() {
+            let next = unsafe { (*entry).next.load(Relaxed) };
+            unsafe { drop(Box::from_raw(entry)) };
+            entry = next;
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for Pool<T> {}
+unsafe impl<T: Send> Sync for Pool<T> {}
+
+impl<'a, T> Deref for PoolGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &(*self.entry).val }
+    }
+}
+
+impl<'a, T> DerefMut for PoolGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut (*self.entry).val }
+    }
+}
+
+impl<'a, T> Drop for PoolGuard<'a, T> {
+    fn drop(&mut self) {
+        match self.pooled {
+            true => self.pool.give_back(self.entry),
+            false => unsafe { drop(Box::from_raw(self.entry)) },
+        }
+    }
+}
+
+unsafe impl<'a, T: Send> Send for PoolGuard<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for PoolGuard<'a, T> {}
+
+#[cfg(test)]
+mod test {
+    extern crate crossbeam;
+    use self::crossbeam::scope;
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering::SeqCst;
+
+    #[test]
+    fn test_reuse() {
+        let pool = Pool::new(|| Vec::with_capacity(64));
+        {
+            let mut buf = pool.get();
+            buf.push(1);
+        }
+        let buf = pool.get();
+        // The same buffer, contents and all
+        assert_eq!(*buf, vec![1]);
+        assert_eq!(pool.hits(), 1);
+        assert_eq!(pool.misses(), 1);
+    }
+
+    #[test]
+    fn test_overflow() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        struct Counted(Arc<AtomicUsize>);
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, SeqCst);
+            }
+        }
+
+        let config = PoolConfig { max_size: 1, ..PoolConfig::default() };
+        let make = drops.clone();
+        let pool = Pool::with_config(config, move || Counted(make.clone()));
+        let first = pool.get();
+        let extra = pool.get();
+        assert_eq!(pool.misses(), 2);
+        drop(extra);
+        assert_eq!(drops.load(SeqCst), 1);
+        drop(first);
+        assert_eq!(drops.load(SeqCst), 1);
+        drop(pool.get());
+        assert_eq!(pool.hits(), 1);
+        drop(pool);
+        assert_eq!(drops.load(SeqCst), 2);
+    }
+
+    #[test]
+    fn test_block() {
+        let config = PoolConfig {
+            max_size: 2,
+            overflow: Overflow::Block,
+            thread_caches: true,
+        };
+        let pool = Pool::with_config(config, || 0usize);
+        scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..1000 {
+                        *pool.get() += 1;
+                    }
+                });
+            }
+        });
+        assert!(pool.misses() <= 2);
+        assert_eq!(pool.hits() + pool.misses(), 4000);
+        assert_eq!(*pool.get() + *pool.get(), 4000);
+    }
+
+    // Objects are handed out and given back as fast as possible, which keeps
+    // reusing the same few entries. An ABA would hand one object to two threads
+    #[test]
+    fn test_mt_exclusive() {
+        let num_run: usize = 20000;
+        let pool = Pool::new(|| AtomicUsize::new(0));
+        scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..num_run {
+                        let a = pool.get();
+                        let b = pool.get();
+                        assert_eq!(a.fetch_add(1, SeqCst), 0);
+                        assert_eq!(b.fetch_add(1, SeqCst), 0);
+                        a.fetch_sub(1, SeqCst);
+                        b.fetch_sub(1, SeqCst);
+                    }
+                });
+            }
+        });
+        assert_eq!(pool.hits() + pool.misses(), 8 * num_run);
+        assert!(pool.misses() <= 8);
+    }
+}
@@ -0,0 +1,31 @@
+//! Fixtures shared by the tests
+
+use std::fmt::Debug;
+use std::thread;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::SeqCst;
+
+use epoch;
+
+/// Counts its drops, carrying a value for readers to check
+pub struct Counted<'a, T = ()>(pub T, pub &'a AtomicUsize);
+
+impl<'a, T> Drop for Counted<'a, T> {
+    fn drop(&mut self) {
+        self.1.fetch_add(1, SeqCst);
+    }
+}
+
+/// Flushes the epoch until get returns expected, and fails if it never does
+///
+/// Guards held by tests running alongside can keep the epoch back for a while
+pub fn flush_until<T: PartialEq + Debug, F: Fn() -> T>(get: F, expected: T) {
+    for _ in 0..10000 {
+        if get() == expected {
+            return;
+        }
+        epoch::flush();
+        thread::yield_now();
+    }
+    assert_eq!(get(), expected);
+}
@@ -0,0 +1,13 @@
+//! A small index per thread, for spreading threads over slots and shards
+
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+
+static NEXT_THREAD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local!(static THREAD_INDEX: usize = NEXT_THREAD.fetch_add(1, Relaxed));
+
+/// Returns the index of the current thread, handed out in the order threads first ask
+pub(crate) fn thread_index() -> usize {
+    THREAD_INDEX.with(|i| *i)
+}
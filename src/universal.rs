@@ -0,0 +1,357 @@
+//! Herlihy's universal construction
+//!
+//! Wraps any sequential object whose state can be cloned. The current state
+//! is an immutable version behind an ExclusivePtr, and an operation clones it,
+//! applies itself to the copy and store_conditionals the copy in. That makes
+//! every sequential object lock-free, at the cost of a clone per operation.
+//! Old versions are retired to the epoch, since readers may hold them.
+//!
+//! With announce slots, an operation is also published in a slot before it
+//! starts, and whoever builds a version applies every announced operation it
+//! finds, keeping their results in the version. Whoever builds on a version
+//! first delivers its results to the slots, so an announced operation has
+//! its result at the latest once two versions were stored after it was
+//! announced, which makes it wait-free. Only a thread which can't get a slot
+//! falls back to retrying on its own.
+//!
+//! Slots are reused, so each announcement carries a round number and results
+//! are only delivered to the round they were computed for.
+
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize};
+use std::sync::atomic::Ordering::{Relaxed, Acquire, Release, SeqCst};
+
+use ordering;
+use epoch::{self, Guard};
+use ExclusivePtr;
+
+// The low bit of an announcement's round, set while its operation waits for a result
+const PENDING: usize = 1;
+
+/// A sequential object which can be wrapped by Universal
+pub trait Sequential: Clone {
+    type Op;
+    type Output;
+
+    fn apply(&mut self, op: &Self::Op) -> Self::Output;
+}
+
+// The result of an announced operation applied in a version
+struct Helped<R> {
+    slot: usize,
+    round: usize,
+    result: *mut R,
+}
+
+struct Version<S: Sequential> {
+    state: S,
+    // Handed over to the slots once the version is stored, never freed with it
+    helped: Vec<Helped<S::Output>>,
+}
+
+struct Announce<Op, R> {
+    active: AtomicBool,
+    round: AtomicUsize,
+    op: AtomicPtr<Op>,
+    result: ExclusivePtr<R>,
+}
+
+pub struct Universal<S: Sequential> {
+    current: ExclusivePtr<Version<S>>,
+    slots: Box<[Announce<S::Op, S::Output>]>,
+}
+
+impl<S: Sequential> Universal<S> {
+
+    /// Wraps the state in a lock-free object
+    pub fn new(state: S) -> Universal<S> {
+        Universal::wait_free(state, 0)
+    }
+
+    /// Wraps the state in an object which is wait-free for up to
+    /// max_threads threads applying operations at once
+    pub fn wait_free(state: S, max_threads: usize) -> Universal<S> {
+        let version = Box::new(Version {
+            state,
+            helped: Vec::new(),
+        });
+        Universal {
+            current: ExclusivePtr::new(Box::into_raw(version)),
+            slots: (0..max_threads).map(|_| Announce {
+                active: AtomicBool::new(false),
+                round: AtomicUsize::new(0),
+                op: AtomicPtr::new(ptr::null_mut()),
+                result: ExclusivePtr::NULL,
+            }).collect::<Vec<_>>().into_boxed_slice(),
+        }
+    }
+
+    /// Applies the operation to the current state, returning its result
+    pub fn apply(&self, op: S::Op) -> S::Output {
+        self.apply_counted(op).0
+    }
+
+    // Also returns the number of versions this thread tried to store
+    pub(crate) fn apply_counted(&self, op: S::Op) -> (S::Output, usize) {
+        let guard = epoch::pin();
+        if let Some(slot) = self.claim() {
+            return unsafe { self.apply_announced(slot, op, &guard) };
+        }
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            if let Ok(Some(result)) = unsafe { self.attempt(Some(&op), &guard) } {
+                return (result, attempts);
+            }
+        }
+    }
+
+    /// Borrows the current state, which stays alive for the life of the guard
+    pub fn get<'g>(&'g self, _: &'g Guard) -> &'g S {
+        unsafe { &(*self.current.load(Acquire)).state }
+    }
+
+    fn claim(&self) -> Option<usize> {
+        self.slots.iter().position(|slot| {
+            !slot.active.load(Relaxed) &&
+            slot.active.compare_exchange(false, true, Acquire, Relaxed).is_ok()
+        })
+    }
+
+    unsafe fn apply_announced(&self, slot: usize, op: S::Op, guard: &Guard)
+                              -> (S::Output, usize) {
+        let ann = &self.slots[slot];
+        let op = Box::into_raw(Box::new(op));
+        let round = ((ann.round.load(Relaxed) >> 1) + 1) << 1;
+        ann.op.store(op, Relaxed);
+        ann.round.store(round | PENDING, SeqCst);
+        let mut attempts = 0;
+        while ann.result.load(Acquire).is_null() {
+            attempts += 1;
+            let _ = self.attempt(None, guard);
+        }
+
+        // Late deliverers check the round after linking the result, so
+        // it has to move on before the result is cleared
+        ann.round.store(round, SeqCst);
+        let mut ll = ann.result.load_linked(ordering::Acquire);
+        let result = loop {
+            let result = ll.get();
+            match ll.store_conditional(ptr::null_mut(), ordering::Relaxed) {
+                Ok(()) => break result,
+                Err(fail) => ll = fail.into_link(),
+            }
+        };
+        // Builders may still be reading the operation
+        guard.retire(op);
+        ann.active.store(false, Release);
+        (*Box::from_raw(result), attempts)
+    }
+
+    // Tries to store a new version with the announced operations and own applied,
+    // returning the result of own if it did
+    unsafe fn attempt(&self, own: Option<&S::Op>, guard: &Guard) -> Result<Option<S::Output>, ()> {
+        let mut ll = self.current.load_linked(ordering::Acquire);
+        let cur = ll.get();
+        self.deliver(&*cur);
+
+        let mut state = (*cur).state.clone();
+        let mut helped = Vec::new();
+        for (i, ann) in self.slots.iter().enumerate() {
+            let round = ann.round.load(SeqCst);
+            if round & PENDING == 0 || !ann.result.load(SeqCst).is_null() {
+                continue;
+            }
+            let op = ann.op.load(Acquire);
+            // The operation could be from a later round if the slot moved on
+            if ann.round.load(SeqCst) != round {
+                continue;
+            }
+            helped.push(Helped {
+                slot: i,
+                round,
+                result: Box::into_raw(Box::new(state.apply(&*op))),
+            });
+        }
+        let result = own.map(|op| state.apply(op));
+
+        let new = Box::into_raw(Box::new(Version {
+            state,
+            helped,
+        }));
+        // Only give up once another version was stored, so spurious
+        // failures don't count against the bound on attempts
+        while ll.get() == cur {
+            match ll.store_conditional(new, ordering::Release) {
+                Ok(()) => {
+                    guard.retire(cur);
+                    self.deliver(&*new);
+                    return Ok(result);
+                },
+                Err(fail) => ll = fail.into_link(),
+            }
+        }
+        // Nobody saw the version, so its results were never delivered
+        let new = Box::from_raw(new);
+        for h in &new.helped {
+            drop(Box::from_raw(h.result));
+        }
+        Err(())
+    }
+
+    // Hands the results of a stored version to the slots still waiting for them
+    fn deliver(&self, version: &Version<S>) {
+        for h in &version.helped {
+            let ann = &self.slots[h.slot];
+            let mut ll = ann.result.load_linked(ordering::Acquire);
+            while ll.get().is_null() && ann.round.load(SeqCst) == h.round {
+                match ll.store_conditional(h.result, ordering::Release) {
+                    Ok(()) => break,
+                    Err(fail) => ll = fail.into_link(),
+                }
+            }
+        }
+    }
+}
+
+impl<S: Sequential> Drop for Universal<S> {
+    fn drop(&mut self) {
+        // Every announced operation got its result, so the version owns none
+        unsafe { drop(Box::from_raw(self.current.load(Relaxed))) };
+    }
+}
+
+unsafe impl<S: Sequential + Send + Sync> Send for Universal<S>
+    where S::Op: Send + Sync, S::Output: Send {}
+unsafe impl<S: Sequential + Send + Sync> Sync for Universal<S>
+    where S::Op: Send + Sync, S::Output: Send {}
+
+#[cfg(test)]
+mod test {
+    use crossbeam::scope;
+    use super::*;
+    use test_util::flush_until;
+    use std::collections::VecDeque;
+
+    #[derive(Clone)]
+    struct Queue(VecDeque<usize>);
+
+    enum QueueOp {
+        Push(usize),
+        Pop,
+    }
+
+    impl Sequential for Queue {
+        type Op = QueueOp;
+        type Output = Option<usize>;
+
+        fn apply(&mut self, op: &QueueOp) -> Option<usize> {
+            match *op {
+                QueueOp::Push(v) => {
+                    self.0.push_back(v);
+                    None
+                },
+                QueueOp::Pop => self.0.pop_front(),
+            }
+        }
+    }
+
+    // Hands out consecutive tickets, so every result must be seen exactly once
+    #[derive(Clone)]
+    struct Tickets(usize);
+
+    impl Sequential for Tickets {
+        type Op = ();
+        type Output = usize;
+
+        fn apply(&mut self, _: &()) -> usize {
+            self.0 += 1;
+            self.0 - 1
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let queue = Universal::new(Queue(VecDeque::new()));
+        assert_eq!(queue.apply(QueueOp::Pop), None);
+        for i in 0..10 {
+            queue.apply(QueueOp::Push(i));
+        }
+        assert_eq!(queue.apply(QueueOp::Pop), Some(0));
+        let guard = epoch::pin();
+        assert_eq!(queue.get(&guard).0.len(), 9);
+    }
+
+    fn run_tickets(tickets: &Universal<Tickets>, num_threads: usize, num_run: usize) {
+        let taken: Vec<Vec<usize>> = scope(|scope| {
+            let handles: Vec<_> = (0..num_threads).map(|_| {
+                scope.spawn(move || {
+                    (0..num_run).map(|_| tickets.apply(())).collect::<Vec<_>>()
+                })
+            }).collect();
+            handles.into_iter().map(|h| h.join()).collect()
+        });
+        for t in &taken {
+            assert!(t.windows(2).all(|w| w[0] < w[1]));
+        }
+        let mut all: Vec<_> = taken.into_iter().flatten().collect();
+        all.sort();
+        assert_eq!(all, (0..num_threads * num_run).collect::<Vec<_>>());
+    }
+
+    // Counts the copies of the state which are alive
+    struct Tracked<'a>(usize, &'a AtomicUsize);
+
+    impl<'a> Clone for Tracked<'a> {
+        fn clone(&self) -> Tracked<'a> {
+            self.1.fetch_add(1, SeqCst);
+            Tracked(self.0, self.1)
+        }
+    }
+
+    impl<'a> Drop for Tracked<'a> {
+        fn drop(&mut self) {
+            self.1.fetch_sub(1, SeqCst);
+        }
+    }
+
+    impl<'a> Sequential for Tracked<'a> {
+        type Op = Vec<usize>;
+        type Output = usize;
+
+        fn apply(&mut self, op: &Vec<usize>) -> usize {
+            self.0 += op.len();
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_mt_drops() {
+        let live = AtomicUsize::new(1);
+        let tracked = Universal::wait_free(Tracked(0, &live), 2);
+        scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..1000 {
+                        tracked.apply(vec![0; 4]);
+                    }
+                });
+            }
+        });
+        assert_eq!(tracked.apply(Vec::new()), 16000);
+        drop(tracked);
+        flush_until(|| live.load(SeqCst), 0);
+    }
+
+    #[test]
+    fn test_mt_lock_free() {
+        run_tickets(&Universal::new(Tickets(0)), 4, 10000);
+    }
+
+    // Two more threads than slots, so some operations fall back to
+    // retrying on their own while the rest are helped along
+    #[test]
+    fn test_mt_wait_free() {
+        run_tickets(&Universal::wait_free(Tickets(0), 4), 6, 10000);
+    }
+}
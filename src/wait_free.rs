@@ -0,0 +1,159 @@
+//! A cell with wait-free updates
+//!
+//! An update loop on an ExclusiveData is only lock-free: some thread always
+//! wins the store_conditional, but a given thread can lose to the others for
+//! as long as they keep updating. A WaitFreeCell announces each update before
+//! trying it, and every successful store_conditional applies the updates it
+//! finds announced, so the others help a losing thread along instead of
+//! starving it.
+//!
+//! It is a Universal over a single value. An announced update has its result
+//! after its thread tried at most three versions: the first version stored
+//! after the announcement may have been built without it, the next one can't
+//! have been, and one more pass hands the result over. Threads beyond
+//! max_threads can't announce and fall back to being lock-free.
+
+use std::mem;
+use std::sync::atomic::Ordering::Relaxed;
+
+use ordering;
+use epoch;
+use universal::{Sequential, Universal};
+use ExclusiveUsize;
+
+type Update<T> = Box<dyn Fn(T) -> T + Send + Sync>;
+
+#[derive(Clone)]
+struct Value<T>(T);
+
+impl<T: Clone> Sequential for Value<T> {
+    type Op = Update<T>;
+    type Output = T;
+
+    fn apply(&mut self, update: &Update<T>) -> T {
+        let new = update(self.0.clone());
+        mem::replace(&mut self.0, new)
+    }
+}
+
+pub struct WaitFreeCell<T: Clone> {
+    inner: Universal<Value<T>>,
+    max_attempts: ExclusiveUsize,
+}
+
+impl<T: Clone> WaitFreeCell<T> {
+
+    /// Creates a cell which is wait-free for up to max_threads threads updating at once
+    pub fn new(val: T, max_threads: usize) -> WaitFreeCell<T> {
+        WaitFreeCell {
+            inner: Universal::wait_free(Value(val), max_threads),
+            max_attempts: ExclusiveUsize::new(0),
+        }
+    }
+
+    pub fn load(&self) -> T {
+        let guard = epoch::pin();
+        self.inner.get(&guard).0.clone()
+    }
+
+    /// Replaces the value with f of it, returning the previous value
+    ///
+    /// Other threads may run f as well while helping, and on values which
+    /// never get stored, so it should have no effects besides its result
+    pub fn update<F>(&self, f: F) -> T
+        where F: Fn(T) -> T + Send + Sync + 'static {
+        let (prev, attempts) = self.inner.apply_counted(Box::new(f));
+        self.record(attempts);
+        prev
+    }
+
+    /// Stores the value, returning the previous one
+    pub fn swap(&self, val: T) -> T
+        where T: Send + Sync + 'static {
+        self.update(move |_| val.clone())
+    }
+
+    /// The most versions a single update has tried to store
+    pub fn max_attempts(&self) -> usize {
+        self.max_attempts.load(Relaxed)
+    }
+
+    fn record(&self, attempts: usize) {
+        let mut ll = self.max_attempts.load_linked(ordering::Relaxed);
+        while attempts > ll.get() {
+            match ll.store_conditional(attempts, ordering::Relaxed) {
+                Ok(()) => return,
+                Err(fail) => ll = fail.into_link(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crossbeam::scope;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::thread;
+    use super::*;
+    use test_util::flush_until;
+
+    #[test]
+    fn test_update() {
+        let cell = WaitFreeCell::new(5, 1);
+        assert_eq!(cell.update(|v| v * 2), 5);
+        assert_eq!(cell.swap(3), 10);
+        assert_eq!(cell.update(|v| v + 1), 3);
+        assert_eq!(cell.load(), 4);
+        assert_eq!(cell.max_attempts(), 1);
+    }
+
+    // Fast threads hammer the cell until one thread, whose update yields
+    // before returning, is done, so on its own that thread would nearly always
+    // lose the race. Every update has to finish within the bound anyway,
+    // and every update and swapped in value must be dropped in the end.
+    #[test]
+    fn test_mt_starvation() {
+        let num_slow = 200;
+        let num_fast = 4;
+        let cell = WaitFreeCell::new(0usize, num_fast + 1);
+        let done = AtomicBool::new(false);
+        let token = Arc::new(());
+
+        let fast: usize = scope(|scope| {
+            let handles: Vec<_> = (0..num_fast).map(|_| {
+                let (cell, done, token) = (&cell, &done, &token);
+                scope.spawn(move || {
+                    let mut updates = 0;
+                    while !done.load(SeqCst) {
+                        let token = token.clone();
+                        cell.update(move |v| {
+                            let _ = &token;
+                            v + 1
+                        });
+                        updates += 1;
+                    }
+                    updates
+                })
+            }).collect();
+            for _ in 0..num_slow {
+                cell.update(|v| {
+                    thread::yield_now();
+                    v + (1 << 20)
+                });
+            }
+            done.store(true, SeqCst);
+            handles.into_iter().map(|h| h.join()).sum()
+        });
+
+        assert_eq!(cell.load(), (num_slow << 20) + fast);
+        assert!(cell.max_attempts() <= 3, "an update took {} attempts", cell.max_attempts());
+
+        let swapped = WaitFreeCell::new(token.clone(), 1);
+        swapped.swap(token.clone());
+        drop(swapped);
+        drop(cell);
+        flush_until(|| Arc::strong_count(&token), 1);
+    }
+}